@@ -0,0 +1,174 @@
+//! Measures the throughput of writing to and reading from a queue,
+//! compared to writing and reading the same bytes to a file directly.
+//! Run via `cargo bench`.
+//!
+//! This doesn't use a benchmarking framework, so the numbers are only
+//! indicative. Data is flushed but not synced, and is read back while
+//! it's cached, so they reflect the speed of encoding and decoding,
+//! not the disk.
+
+extern crate depot;
+extern crate tempdir;
+
+use depot::QueueOptions;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::time::{Duration, Instant};
+
+/// The number of bytes written by each benchmark.
+const TOTAL_BYTES: usize = 256 * 1024 * 1024;
+
+fn main() {
+    for &size in &[12, 256, 4096] {
+        let item = item(size);
+        let count = TOTAL_BYTES / size;
+
+        report("raw", size, count, raw(&item, count));
+        report("append", size, count, append(&item, count));
+        report("append_batch", size, count, append_batch(&item, count));
+        report("raw read", size, count, raw_read(&item, count));
+        report("stream", size, count, stream(&item, count, false));
+        report("stream mmap", size, count, stream(&item, count, true));
+    }
+}
+
+/// Returns an item of the provided size, whose bytes rarely need to be
+/// escaped, as is typical of text.
+fn item(size: usize) -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog - "
+        .iter()
+        .cycle()
+        .take(size)
+        .cloned()
+        .collect()
+}
+
+fn raw(item: &[u8], count: usize) -> Duration {
+    let tmp_dir = tempdir::TempDir::new("depot-bench").unwrap();
+    let file = File::create(tmp_dir.path().join("raw")).unwrap();
+    let mut writer = BufWriter::with_capacity(8192, file);
+
+    let start = Instant::now();
+
+    for _ in 0..count {
+        writer.write_all(item).unwrap();
+        writer.write_all(b"\n").unwrap();
+    }
+
+    writer.flush().unwrap();
+
+    start.elapsed()
+}
+
+fn append(item: &[u8], count: usize) -> Duration {
+    let tmp_dir = tempdir::TempDir::new("depot-bench").unwrap();
+    let mut queue = QueueOptions::new().open(tmp_dir.path()).unwrap();
+
+    let start = Instant::now();
+
+    for _ in 0..count {
+        queue.append(item).unwrap();
+    }
+
+    queue.flush().unwrap();
+
+    start.elapsed()
+}
+
+fn append_batch(item: &[u8], count: usize) -> Duration {
+    let tmp_dir = tempdir::TempDir::new("depot-bench").unwrap();
+    let mut queue = QueueOptions::new().open(tmp_dir.path()).unwrap();
+    let batch = vec![item; 1000];
+
+    let start = Instant::now();
+
+    for _ in 0..count / batch.len() {
+        queue.append_batch(&batch).unwrap();
+    }
+
+    queue.flush().unwrap();
+
+    start.elapsed()
+}
+
+fn raw_read(item: &[u8], count: usize) -> Duration {
+    let tmp_dir = tempdir::TempDir::new("depot-bench").unwrap();
+    let path = tmp_dir.path().join("raw");
+
+    {
+        let file = File::create(&path).unwrap();
+        let mut writer = BufWriter::new(file);
+
+        for _ in 0..count {
+            writer.write_all(item).unwrap();
+            writer.write_all(b"\n").unwrap();
+        }
+    }
+
+    let mut buf = vec![0; 65536];
+    let mut total = 0;
+
+    let start = Instant::now();
+    let mut file = File::open(&path).unwrap();
+
+    loop {
+        match file.read(&mut buf).unwrap() {
+            0 => break,
+            n => total += n,
+        }
+    }
+
+    let elapsed = start.elapsed();
+
+    assert_eq!(total as u64, fs::metadata(&path).unwrap().len());
+
+    elapsed
+}
+
+fn stream(item: &[u8], count: usize, mmap: bool) -> Duration {
+    let tmp_dir = tempdir::TempDir::new("depot-bench").unwrap();
+
+    // only sections that are no longer written to are mapped, so
+    // the data is split over several of them
+    let mut queue = QueueOptions::new()
+        .max_file_size(TOTAL_BYTES as u32 / 8)
+        .mmap(mmap)
+        .open(tmp_dir.path())
+        .unwrap();
+    let batch = vec![item; 1000];
+
+    for _ in 0..count / batch.len() {
+        queue.append_batch(&batch).unwrap();
+    }
+
+    queue.flush().unwrap();
+
+    let start = Instant::now();
+    let mut streamer = queue.stream(None).unwrap();
+    let mut read = 0;
+
+    while let Some(data) = streamer.next().unwrap().map(|item| item.data) {
+        assert_eq!(data.len(), item.len());
+        read += 1;
+    }
+
+    let elapsed = start.elapsed();
+
+    assert_eq!(read, count / batch.len() * batch.len());
+
+    elapsed
+}
+
+fn report(name: &str, size: usize, count: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+
+    println!(
+        "{:>12} {:>5} byte items: {:>8.1} MB/s {:>6.2}M items/s",
+        name,
+        size,
+        (size * count) as f64 / secs / 1_000_000.0,
+        count as f64 / secs / 1_000_000.0,
+    );
+}
@@ -0,0 +1,295 @@
+use error::{DepotError, Result};
+use queue::{create_dir_all_synced, sync_dir, QueueItem, QueueStreamer};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The directory, relative to the queue, that consumer offsets are
+/// stored in. Its name can't be confused with a section directory.
+const CONSUMERS_DIR: &str = "consumers";
+
+/// A named consumer of a queue, which streams items and durably
+/// records the id of the last item it has processed, so that it can
+/// resume from where it left off.
+///
+/// Consumers are created via `Queue::consumer`. Committing is left
+/// to the caller, so items are processed at least once -- any that
+/// were processed but not committed before a crash are read again.
+///
+/// # Examples
+///
+/// ```no_run
+/// use depot::Queue;
+///
+/// let queue = Queue::new("/tmp/my-queue");
+/// let mut consumer = queue.consumer("billing").unwrap();
+///
+/// while let Some(id) = consumer.next().unwrap().map(|item| item.id) {
+///     // ... process the item ...
+///     consumer.commit(id).unwrap();
+/// }
+/// ```
+pub struct Consumer {
+    committed: Option<u64>,
+    name: String,
+    path_buf: PathBuf,
+    skip: Option<u64>,
+    streamer: QueueStreamer,
+}
+
+impl Consumer {
+    pub(crate) fn new(
+        name: &str,
+        path_buf: PathBuf,
+        committed: Option<u64>,
+        streamer: QueueStreamer,
+    ) -> Consumer {
+        Consumer {
+            committed,
+            name: name.to_string(),
+            path_buf,
+            skip: committed,
+            streamer,
+        }
+    }
+
+    /// Returns the name of the consumer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the id of the last item that was committed, if any.
+    pub fn committed(&self) -> Option<u64> {
+        self.committed
+    }
+
+    /// Returns the next item that hasn't been committed, if any. See
+    /// `QueueStreamer::next` for details.
+    pub fn next<'a>(&'a mut self) -> Result<Option<QueueItem<'a>>> {
+        self.streamer.advance(false);
+
+        // The stream resumes at the committed item, which has already
+        // been processed, so it's skipped. If that item no longer
+        // exists, e.g. due to retention, the stream resumes at the
+        // earliest item after it instead.
+        if let Some(skip) = self.skip {
+            let id = match self.streamer.current_all() {
+                Ok(Some((item, _))) => Some(item.id),
+                _ => None,
+            };
+
+            if let Some(id) = id {
+                self.skip = None;
+
+                if id == skip {
+                    self.streamer.advance(false);
+                }
+            }
+        }
+
+        self.streamer.current()
+    }
+
+    /// Returns the next item that hasn't been committed, waiting up to
+    /// the provided timeout for one to be available, or `None` if none
+    /// was. See `QueueStreamer::next_blocking` for details.
+    pub fn next_blocking<'a>(&'a mut self, timeout: Duration) -> Result<Option<QueueItem<'a>>> {
+        let deadline = Instant::now() + timeout;
+
+        // As with `next`, the committed item is skipped if it's the
+        // first one to become available.
+        if let Some(skip) = self.skip {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            let id = match self.streamer.next_blocking(remaining)? {
+                Some(item) => item.id,
+                None => return Ok(None),
+            };
+
+            self.skip = None;
+
+            if id != skip {
+                return self.streamer.current();
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        self.streamer.next_blocking(remaining)
+    }
+
+    /// Durably records the provided id as the last item processed by
+    /// this consumer. Subsequent consumers with the same name resume
+    /// with the item after it.
+    ///
+    /// The offset is written to a temporary file which then replaces
+    /// the previous one, so a crash can't leave a partial offset.
+    pub fn commit(&mut self, id: u64) -> Result<()> {
+        let parent = self
+            .path_buf
+            .parent()
+            .ok_or_else(|| io::Error::other("consumer path has no parent"))?;
+        let tmp_path = parent.join(format!(".{}.tmp", self.name));
+
+        create_dir_all_synced(parent)?;
+
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(format!("{}\n", id).as_bytes())?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &self.path_buf)?;
+        sync_dir(parent)?;
+
+        self.committed = Some(id);
+
+        Ok(())
+    }
+}
+
+/// Returns the path that the offset of the named consumer is stored
+/// at, validating the name so that it can't escape the directory.
+pub(crate) fn consumer_path(base: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if valid {
+        Ok(base.join(CONSUMERS_DIR).join(name))
+    } else {
+        Err(DepotError::InvalidConfig(
+            "consumer names must be alphanumeric, and may contain '-', '_' and '.'".to_string(),
+        ))
+    }
+}
+
+/// Reads the committed offset at the provided path, if any.
+pub(crate) fn read_offset(path: &Path) -> Result<Option<u64>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    match contents.trim_end().parse() {
+        Ok(id) => Ok(Some(id)),
+
+        Err(_) => Err(DepotError::corrupt(
+            0,
+            format!("invalid consumer offset in {}", path.display()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use consumer::*;
+    use queue::tests::small_queue;
+    use std::thread;
+
+    #[test]
+    fn test_consumer() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let (mut queue, ids) = small_queue(tmp_dir.path(), 50);
+
+        let mut consumer = queue.consumer("billing").unwrap();
+        assert_eq!(consumer.name(), "billing");
+        assert_eq!(consumer.committed(), None);
+
+        for expected in ids.iter().take(10) {
+            let id = consumer.next().unwrap().unwrap().id;
+            assert_eq!(id, *expected);
+            consumer.commit(id).unwrap();
+        }
+
+        // uncommitted items are read again
+        assert_eq!(consumer.next().unwrap().unwrap().id, ids[10]);
+
+        let mut consumer = queue.consumer("billing").unwrap();
+        assert_eq!(consumer.committed(), Some(ids[9]));
+        assert_eq!(consumer.next().unwrap().unwrap().data, b"item 10");
+
+        // consumers are independent of each other
+        let mut other = queue.consumer("audit").unwrap();
+        assert_eq!(other.next().unwrap().unwrap().data, b"item 0");
+
+        // the committed item may have been deleted by retention
+        queue.truncate_before(ids[30]).unwrap();
+        let first = queue.first_offset().unwrap().unwrap();
+        let mut consumer = queue.consumer("billing").unwrap();
+        assert_eq!(consumer.next().unwrap().unwrap().id, first);
+
+        // the last item is committed, so there's nothing more to read
+        consumer.commit(ids[49]).unwrap();
+        let mut consumer = queue.consumer("billing").unwrap();
+        assert!(consumer.next().unwrap().is_none());
+
+        queue.append(b"item 50").unwrap();
+        queue.flush().unwrap();
+
+        assert_eq!(consumer.next().unwrap().unwrap().data, b"item 50");
+    }
+
+    #[test]
+    fn test_consumer_next_blocking() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let (mut queue, ids) = small_queue(tmp_dir.path(), 5);
+
+        let mut consumer = queue.consumer("billing").unwrap();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(consumer.next_blocking(timeout).unwrap().unwrap().id, ids[0]);
+        consumer.commit(ids[4]).unwrap();
+
+        // the committed item is skipped, leaving nothing to read
+        let mut consumer = queue.consumer("billing").unwrap();
+        assert!(consumer.next_blocking(timeout).unwrap().is_none());
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            queue.append(b"item 5").unwrap();
+            queue.flush().unwrap();
+        });
+
+        let item = consumer.next_blocking(Duration::from_secs(10)).unwrap();
+        assert_eq!(item.unwrap().data, b"item 5");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_consumer_path() {
+        let base = Path::new("/tmp/queue");
+
+        assert_eq!(
+            consumer_path(base, "billing-v2.1").unwrap(),
+            base.join("consumers").join("billing-v2.1")
+        );
+
+        for name in &["", ".", "..", ".hidden", "a/b", "../b", "a b"] {
+            assert!(consumer_path(base, name).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_offset() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("offset");
+
+        assert_eq!(read_offset(&path).unwrap(), None);
+
+        fs::write(&path, "12345\n").unwrap();
+        assert_eq!(read_offset(&path).unwrap(), Some(12345));
+
+        fs::write(&path, "garbage").unwrap();
+        match read_offset(&path) {
+            Err(DepotError::Corrupt { offset: 0, .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+/// The reversed Castagnoli polynomial, as used by iSCSI, ext4, etc.
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+/// Lookup table for the byte-at-a-time algorithm, computed at
+/// compile time so that no initialization is required.
+static TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+
+            j += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// Computes the CRC32C (Castagnoli) checksum of the provided data.
+///
+/// This is a simple table-driven implementation. It's not as fast
+/// as the hardware accelerated variants, but it's portable and
+/// avoids pulling in a CRC crate for one function.
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crc32c::*;
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xE306_9283);
+        assert_eq!(checksum(&[0u8; 32]), 0x8A91_36AA);
+        assert_eq!(checksum(&[0xFFu8; 32]), 0x62A8_AB43);
+    }
+}
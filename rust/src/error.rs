@@ -0,0 +1,181 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// A specialized `Result` type for Depot operations.
+pub type Result<T> = result::Result<T, DepotError>;
+
+/// The errors that can be returned by Depot.
+///
+/// These convert to and from `io::Error`, so they can be used with
+/// `?` in functions that return `io::Result`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DepotError {
+    /// An I/O error occurred while accessing the queue.
+    Io(io::Error),
+
+    /// The data at an offset of the queue is malformed, e.g. due to
+    /// an item's checksum not matching its data, or the file having
+    /// been modified by something other than Depot.
+    Corrupt { offset: u64, reason: String },
+
+    /// An item is larger than the maximum item size of the queue.
+    ItemTooLarge { size: usize, max_item_size: u32 },
+
+    /// The queue has no more sections available to write to.
+    QueueFull,
+
+    /// An offset doesn't refer to a valid position in the queue.
+    InvalidOffset(u64),
+
+    /// The options or arguments used to open a queue, or a consumer
+    /// of it, are invalid.
+    InvalidConfig(String),
+
+    /// Another writer holds the queue's lock. Its pid is provided if
+    /// it could be determined.
+    Locked { pid: Option<u32> },
+}
+
+impl DepotError {
+    pub(crate) fn corrupt<S: Into<String>>(offset: u64, reason: S) -> DepotError {
+        DepotError::Corrupt {
+            offset,
+            reason: reason.into(),
+        }
+    }
+
+    /// Returns a copy of the error, so that it can be reported more
+    /// than once. I/O errors can't be cloned, so they're recreated
+    /// from their kind and message.
+    pub(crate) fn duplicate(&self) -> DepotError {
+        match *self {
+            DepotError::Io(ref e) => DepotError::Io(io::Error::new(e.kind(), e.to_string())),
+
+            DepotError::Corrupt { offset, ref reason } => {
+                DepotError::corrupt(offset, reason.clone())
+            }
+
+            DepotError::ItemTooLarge {
+                size,
+                max_item_size,
+            } => DepotError::ItemTooLarge {
+                size,
+                max_item_size,
+            },
+
+            DepotError::QueueFull => DepotError::QueueFull,
+
+            DepotError::InvalidOffset(offset) => DepotError::InvalidOffset(offset),
+
+            DepotError::InvalidConfig(ref message) => DepotError::InvalidConfig(message.clone()),
+
+            DepotError::Locked { pid } => DepotError::Locked { pid },
+        }
+    }
+}
+
+impl fmt::Display for DepotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DepotError::Io(ref e) => e.fmt(f),
+
+            DepotError::Corrupt { offset, ref reason } => {
+                write!(f, "corrupt data at offset {}: {}", offset, reason)
+            }
+
+            DepotError::ItemTooLarge {
+                size,
+                max_item_size,
+            } => write!(
+                f,
+                "item of {} bytes exceeds max item size of {} bytes",
+                size, max_item_size
+            ),
+
+            DepotError::QueueFull => write!(f, "queue is full"),
+
+            DepotError::InvalidOffset(offset) => write!(f, "invalid offset {}", offset),
+
+            DepotError::InvalidConfig(ref message) => write!(f, "invalid config: {}", message),
+
+            DepotError::Locked { pid: Some(pid) } => write!(f, "queue locked by pid {}", pid),
+
+            DepotError::Locked { pid: None } => write!(f, "queue locked by another writer"),
+        }
+    }
+}
+
+impl error::Error for DepotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DepotError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DepotError {
+    fn from(e: io::Error) -> DepotError {
+        DepotError::Io(e)
+    }
+}
+
+impl From<DepotError> for io::Error {
+    fn from(e: DepotError) -> io::Error {
+        let kind = match e {
+            DepotError::Io(e) => return e,
+            DepotError::Corrupt { .. } => io::ErrorKind::InvalidData,
+            DepotError::ItemTooLarge { .. } => io::ErrorKind::InvalidInput,
+            DepotError::QueueFull => io::ErrorKind::Other,
+            DepotError::InvalidOffset(_) => io::ErrorKind::InvalidInput,
+            DepotError::InvalidConfig(_) => io::ErrorKind::InvalidInput,
+            DepotError::Locked { .. } => io::ErrorKind::WouldBlock,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::*;
+
+    #[test]
+    fn test_io_conversion() {
+        let e = DepotError::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::NotFound);
+
+        let e = io::Error::from(DepotError::corrupt(42, "invalid byte"));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "corrupt data at offset 42: invalid byte");
+
+        match e.into_inner().unwrap().downcast::<DepotError>() {
+            Ok(e) => match *e {
+                DepotError::Corrupt { offset: 42, .. } => (),
+                ref other => panic!("unexpected error: {:?}", other),
+            },
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let e = DepotError::Io(io::Error::new(io::ErrorKind::NotFound, "missing"));
+
+        match e.duplicate() {
+            DepotError::Io(ref d) => {
+                assert_eq!(d.kind(), io::ErrorKind::NotFound);
+                assert_eq!(d.to_string(), "missing");
+            }
+            ref other => panic!("unexpected error: {:?}", other),
+        }
+
+        assert_eq!(
+            DepotError::InvalidOffset(7).duplicate().to_string(),
+            "invalid offset 7"
+        );
+    }
+}
@@ -1,4 +1,19 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate memchr;
+extern crate memmap2;
+
+pub mod consumer;
+mod crc32c;
+pub mod error;
+mod notify;
 pub mod queue;
 pub mod section;
+mod watch;
 
-pub use queue::{Queue, QueueItem};
+pub use consumer::Consumer;
+pub use error::{DepotError, Result};
+pub use queue::{
+    Queue, QueueItem, QueueOptions, QueueReader, QueueReaderHandle, QueueWriter, ResumePolicy,
+    SyncPolicy,
+};
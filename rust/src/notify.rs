@@ -0,0 +1,92 @@
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Notifies readers in the same process when a writer has written
+/// data to the file, e.g. by flushing, so that they can wait for new
+/// data without watching the filesystem. See `Queue::split`.
+///
+/// Flushes are counted, so a reader can record the count before it
+/// checks for new data and then wait for it to change, without
+/// missing a flush that occurs in between.
+pub(crate) struct Notifier {
+    condvar: Condvar,
+    flushes: Mutex<u64>,
+}
+
+impl Notifier {
+    pub(crate) fn new() -> Notifier {
+        Notifier {
+            condvar: Condvar::new(),
+            flushes: Mutex::new(0),
+        }
+    }
+
+    /// Returns the number of flushes so far.
+    pub(crate) fn flushes(&self) -> u64 {
+        *self.lock()
+    }
+
+    /// Records a flush and wakes any waiting readers.
+    pub(crate) fn notify(&self) {
+        *self.lock() += 1;
+        self.condvar.notify_all();
+    }
+
+    /// Blocks until the number of flushes exceeds the provided one,
+    /// or the timeout has elapsed, returning the number of flushes.
+    pub(crate) fn wait(&self, flushes: u64, timeout: Duration) -> u64 {
+        let deadline = Instant::now() + timeout;
+        let mut current = self.lock();
+
+        while *current <= flushes {
+            let now = Instant::now();
+
+            if now >= deadline {
+                break;
+            }
+
+            current = self
+                .condvar
+                .wait_timeout(current, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        *current
+    }
+
+    // the count remains valid if a thread panicked while holding it
+    fn lock(&self) -> MutexGuard<'_, u64> {
+        self.flushes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_notifier() {
+        let notifier = Arc::new(Notifier::new());
+        assert_eq!(notifier.flushes(), 0);
+
+        // times out if nothing is flushed
+        assert_eq!(notifier.wait(0, Duration::from_millis(10)), 0);
+
+        // returns immediately if a flush has already occurred
+        notifier.notify();
+        assert_eq!(notifier.wait(0, Duration::from_secs(60)), 1);
+
+        let handle = {
+            let notifier = notifier.clone();
+            thread::spawn(move || notifier.wait(1, Duration::from_secs(60)))
+        };
+
+        thread::sleep(Duration::from_millis(10));
+        notifier.notify();
+
+        assert_eq!(handle.join().unwrap(), 2);
+    }
+}
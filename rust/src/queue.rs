@@ -1,15 +1,30 @@
+use consumer;
+use consumer::Consumer;
+use error::{DepotError, Result};
+use notify::Notifier;
+use section;
 use section::{SectionReader, SectionStreamingIterator, SectionWriter};
+use std::cmp;
 use std::ffi::OsStr;
 use std::fs;
-use std::fs::DirEntry;
+use std::fs::{DirEntry, File, OpenOptions, TryLockError};
 use std::io;
+use std::io::prelude::*;
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use watch::Watcher;
 
 const MAX_COMPONENT_VALUE: u16 = 1000;
 
 const MAX_COMPONENT_ENCODED_VALUE: u32 = 1_999_999_999;
 
-#[derive(Debug, PartialEq)]
+/// The file, relative to the queue, that the writer holds a lock on.
+const LOCK_FILE: &str = "lock";
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Component {
     one: u16,
     two: u16,
@@ -41,10 +56,7 @@ impl Component {
                 four: four as u16,
             })
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "encoded component exceeds maximum value",
-            ))
+            Err(io::Error::other("encoded component exceeds maximum value"))
         }
     }
 
@@ -70,10 +82,7 @@ impl Component {
                 four,
             })
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "encoded component exceeds maximum value",
-            ))
+            Err(io::Error::other("encoded component exceeds maximum value"))
         }
     }
 
@@ -143,547 +152,3252 @@ impl Component {
     }
 }
 
-pub struct Queue {
-    component_section: Option<(Component, SectionWriter)>,
+/// Determines when a queue automatically syncs appended items to
+/// disk, trading latency for durability. In all cases, items can
+/// be synced explicitly via `Queue::sync`.
+///
+/// The policy is evaluated as items are appended -- there is no
+/// background thread, so with `Interval`, items appended before
+/// the queue goes idle remain unsynced until the next append or
+/// an explicit sync.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Never sync automatically. This is the default.
+    Never,
+
+    /// Sync after every append.
+    EveryAppend,
+
+    /// Sync once this many items have been appended since the
+    /// last sync.
+    EveryNItems(u64),
+
+    /// Sync once items totalling this many bytes (before
+    /// encoding) have been appended since the last sync.
+    EveryNBytes(u64),
+
+    /// Sync when appending if at least this much time has passed
+    /// since the last sync.
+    Interval(Duration),
+}
+
+/// Determines how a streamer handles an id to resume from that isn't
+/// the start of an item, e.g. because it was read from a stale or
+/// corrupt checkpoint. Starting from such an id would otherwise yield
+/// the remainder of an item, or fail to decode it.
+///
+/// The id is checked once the streamer reaches it, i.e. once the
+/// section has been written up to it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResumePolicy {
+    /// Fail with `DepotError::InvalidOffset`. This is the default.
+    Strict,
+
+    /// Skip forward to the start of the next item.
+    Lenient,
+}
+
+/// Options and flags which can be used to configure how a queue is
+/// opened, in the same spirit as `std::fs::OpenOptions`.
+///
+/// The sizes are recorded in each section when it's created, so
+/// changing them affects new sections only.
+///
+/// # Examples
+///
+/// ```no_run
+/// use depot::QueueOptions;
+///
+/// let mut queue = QueueOptions::new()
+///     .max_file_size(1048576)
+///     .max_item_size(65536)
+///     .checksums(true)
+///     .open("/tmp/my-queue")
+///     .unwrap();
+///
+/// queue.append(b"hello").unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct QueueOptions {
+    checksums: bool,
     max_file_size: u32,
     max_item_size: u32,
-    path_buf: PathBuf,
+    mmap: bool,
     read_chunk_size: u32,
+    resume_policy: ResumePolicy,
+    retention_max_age: Option<Duration>,
+    retention_max_bytes: Option<u64>,
+    retention_max_sections: Option<usize>,
+    sequences: bool,
+    sync_policy: SyncPolicy,
     write_chunk_size: u32,
 }
 
-impl Queue {
-    /// Constructs a new `Queue` that is used to read and
-    /// write items to the filesystem.
-    pub fn new<S: AsRef<OsStr> + ?Sized>(path: &S) -> Queue {
-        let path_buf = PathBuf::from(path);
-
-        Queue {
-            component_section: None,
+impl QueueOptions {
+    /// Creates a blank set of options with the defaults used by
+    /// `Queue::new`, i.e. ~2GB files, 8K items, 64K chunks for
+    /// reading and 8K chunks for writing.
+    pub fn new() -> QueueOptions {
+        QueueOptions {
+            checksums: false,
             max_file_size: 2147287039,
             max_item_size: 8192,
-            path_buf,
-            read_chunk_size: 8192,
+            mmap: false,
+            read_chunk_size: 65536,
+            resume_policy: ResumePolicy::Strict,
+            retention_max_age: None,
+            retention_max_bytes: None,
+            retention_max_sections: None,
+            sequences: false,
+            sync_policy: SyncPolicy::Never,
             write_chunk_size: 8192,
         }
     }
 
-    pub(crate) fn _config<S: AsRef<OsStr> + ?Sized>(
-        path: &S,
-        max_file_size: u32,
-        max_item_size: u32,
-        read_chunk_size: u32,
-        write_chunk_size: u32,
-    ) -> Queue {
-        let path_buf = PathBuf::from(path);
+    /// Enables or disables per-item CRC32C checksums, which allow
+    /// readers to detect items that have been corrupted on disk.
+    ///
+    /// The setting is recorded in the header of each section when
+    /// it's created, so readers don't need to be configured to match.
+    pub fn checksums(&mut self, enabled: bool) -> &mut QueueOptions {
+        self.checksums = enabled;
+        self
+    }
 
-        Queue {
-            component_section: None,
-            max_file_size,
-            max_item_size,
-            path_buf,
-            read_chunk_size,
-            write_chunk_size,
+    /// Sets the size at which a section is considered full, at which
+    /// point subsequent items are written to a new section. A section
+    /// may exceed this by up to the size of one (encoded) item.
+    ///
+    /// It must leave room for an item after the section's 32 byte
+    /// header, so it must be at least 34 bytes.
+    pub fn max_file_size(&mut self, max_file_size: u32) -> &mut QueueOptions {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Sets the maximum size of an item. Larger items require more
+    /// memory for readers, and reduce the largest allowed file size.
+    pub fn max_item_size(&mut self, max_item_size: u32) -> &mut QueueOptions {
+        self.max_item_size = max_item_size;
+        self
+    }
+
+    /// Enables or disables memory-mapping sections that are no longer
+    /// written to, i.e. all but the latest, when streaming. Their items
+    /// are then read directly from the mapping, rather than copied into
+    /// a buffer, which is faster for large scans. The latest section is
+    /// read via a buffer regardless, as is any that can't be mapped.
+    ///
+    /// A mapped section must not be truncated or modified while it's
+    /// being read, which Depot never does, or the reader may crash.
+    /// Deleting it, e.g. via retention, is safe.
+    pub fn mmap(&mut self, enabled: bool) -> &mut QueueOptions {
+        self.mmap = enabled;
+        self
+    }
+
+    /// Sets the number of bytes that readers request from the
+    /// filesystem at a time. Readers buffer at least this many
+    /// bytes, and more if an item doesn't fit.
+    pub fn read_chunk_size(&mut self, read_chunk_size: u32) -> &mut QueueOptions {
+        self.read_chunk_size = read_chunk_size;
+        self
+    }
+
+    /// Sets how streamers handle an id to resume from that isn't the
+    /// start of an item. See `ResumePolicy`.
+    pub fn resume_policy(&mut self, resume_policy: ResumePolicy) -> &mut QueueOptions {
+        self.resume_policy = resume_policy;
+        self
+    }
+
+    /// Deletes sections that were last written to longer ago than
+    /// the provided duration.
+    ///
+    /// Retention is enforced as the queue moves on to a new section
+    /// and via `Queue::enforce_retention`. Sections are deleted from
+    /// the head of the queue, so once a section is deleted, so are
+    /// all of the sections that precede it. The section that is
+    /// currently being written to is never deleted.
+    ///
+    /// Errors while enforcing retention as the queue moves on don't
+    /// fail the append that caused it, and it's retried at the next
+    /// section. `Queue::enforce_retention` returns them.
+    pub fn retention_max_age(&mut self, max_age: Duration) -> &mut QueueOptions {
+        self.retention_max_age = Some(max_age);
+        self
+    }
+
+    /// Deletes the oldest sections while the total size of the queue's
+    /// sections, including their indexes, exceeds the provided number
+    /// of bytes.
+    ///
+    /// See `retention_max_age` for details on how retention is enforced.
+    pub fn retention_max_bytes(&mut self, max_bytes: u64) -> &mut QueueOptions {
+        self.retention_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Deletes the oldest sections while the queue has more than the
+    /// provided number of sections, including the current one.
+    ///
+    /// See `retention_max_age` for details on how retention is enforced.
+    pub fn retention_max_sections(&mut self, max_sections: usize) -> &mut QueueOptions {
+        self.retention_max_sections = Some(max_sections);
+        self
+    }
+
+    /// Enables or disables dense sequence numbers, which number items
+    /// 0, 1, 2, ... in the order they're appended, unlike ids, which
+    /// are positions within sections. Streamers report the sequence of
+    /// each item via `QueueStreamer::sequence`, and can seek to one via
+    /// `QueueStreamer::seek_to_sequence`.
+    ///
+    /// Each section records the sequence of its first item in its
+    /// header, and keeps an index of its items in a file alongside it.
+    /// As with checksums, the setting is recorded in each section when
+    /// it's created, so enabling it for an existing queue takes effect
+    /// from its next section. Sequences continue from the last section
+    /// that had them, counting the items appended while they were
+    /// disabled, or if none had them, count the items from the earliest
+    /// section that still exists.
+    pub fn sequences(&mut self, enabled: bool) -> &mut QueueOptions {
+        self.sequences = enabled;
+        self
+    }
+
+    /// Sets the policy that determines when appended items are
+    /// automatically synced to disk.
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut QueueOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the size of the writer's buffer. Data is written to the
+    /// filesystem when the buffer is full, or when the queue is synced.
+    pub fn write_chunk_size(&mut self, write_chunk_size: u32) -> &mut QueueOptions {
+        self.write_chunk_size = write_chunk_size;
+        self
+    }
+
+    /// Opens a queue at the provided path with these options.
+    ///
+    /// The options are validated, but the queue's directory isn't
+    /// created until it is first written to.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Queue> {
+        self.validate()?;
+
+        let path_buf = path.as_ref().to_path_buf();
+
+        if path_buf.exists() && !path_buf.is_dir() {
+            return Err(DepotError::InvalidConfig(
+                "queue path exists but is not a directory".to_string(),
+            ));
         }
+
+        Ok(self.queue(path_buf))
     }
 
-    pub fn append(&mut self, data: &[u8]) -> io::Result<()> {
-        let advance_and_append = self.with(|ref _component, ref mut section| {
-            if section.is_full() {
-                Ok(true)
-            } else {
-                section.append(data)?;
+    /// Opens a queue at the provided path with these options, and
+    /// acquires its writer lock, waiting until any other writer has
+    /// released it.
+    ///
+    /// A queue opened via `open` acquires the lock when it's first
+    /// written to instead, failing if another writer holds it.
+    pub fn open_writer<P: AsRef<Path>>(&self, path: P) -> Result<Queue> {
+        let mut queue = self.open(path)?;
+        queue.lock(true)?;
+        Ok(queue)
+    }
 
-                Ok(false)
-            }
-        })?;
+    /// Opens a queue at the provided path with these options, and
+    /// acquires its writer lock, failing with `DepotError::Locked`
+    /// if another writer holds it.
+    pub fn try_open_writer<P: AsRef<Path>>(&self, path: P) -> Result<Queue> {
+        let mut queue = self.open(path)?;
+        queue.lock(false)?;
+        Ok(queue)
+    }
 
-        if advance_and_append {
-            self.advance()?;
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(DepotError::InvalidConfig(message));
 
-            self.with(|ref _component, ref mut section2| section2.append(data))
+        if self.max_item_size == 0 || self.max_item_size > section::MAX_ITEM_SIZE {
+            invalid(format!(
+                "max_item_size must be between 1 and {}",
+                section::MAX_ITEM_SIZE
+            ))
+        } else if self.max_file_size < section::MIN_FILE_SIZE
+            || self.max_file_size > section::max_file_size(self.max_item_size)
+        {
+            invalid(format!(
+                "max_file_size must be between {} and {} for a max_item_size of {}",
+                section::MIN_FILE_SIZE,
+                section::max_file_size(self.max_item_size),
+                self.max_item_size
+            ))
+        } else if self.read_chunk_size == 0 {
+            invalid("read_chunk_size must be greater than zero".to_string())
+        } else if self.write_chunk_size == 0 {
+            invalid("write_chunk_size must be greater than zero".to_string())
+        } else if self.sync_policy == SyncPolicy::EveryNItems(0)
+            || self.sync_policy == SyncPolicy::EveryNBytes(0)
+            || self.sync_policy == SyncPolicy::Interval(Duration::from_secs(0))
+        {
+            invalid("sync_policy must have a non-zero threshold".to_string())
+        } else if self.retention_max_age == Some(Duration::from_secs(0))
+            || self.retention_max_bytes == Some(0)
+            || self.retention_max_sections == Some(0)
+        {
+            invalid("retention limits must be greater than zero".to_string())
         } else {
             Ok(())
         }
     }
 
-    pub fn is_empty(&mut self) -> io::Result<bool> {
-        self.with(|ref component, ref mut section| Ok(component.is_empty() && section.is_empty()))
+    fn queue(&self, path_buf: PathBuf) -> Queue {
+        Queue {
+            component_section: None,
+            lock: None,
+            notifier: None,
+            options: self.clone(),
+            path_buf,
+            synced_at: Instant::now(),
+            unsynced_bytes: 0,
+            unsynced_items: 0,
+        }
     }
 
-    pub fn is_full(&mut self) -> io::Result<bool> {
-        self.with(|ref component, ref mut section| Ok(component.is_full() && section.is_full()))
+    /// Opens a queue at the provided path with these options, for
+    /// reading only. See `QueueReader::open`.
+    pub fn open_reader<P: AsRef<Path>>(&self, path: P) -> Result<QueueReader> {
+        self.validate()?;
+
+        let path_buf = path.as_ref().to_path_buf();
+
+        match fs::metadata(&path_buf) {
+            Ok(ref meta) if meta.is_dir() => (),
+
+            Ok(_) => {
+                return Err(DepotError::InvalidConfig(
+                    "queue path exists but is not a directory".to_string(),
+                ))
+            }
+
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("queue does not exist at {}", path_buf.display()),
+                )
+                .into())
+            }
+
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(QueueReader {
+            notifier: None,
+            options: self.clone(),
+            path_buf,
+        })
     }
 
-    pub fn last_id(&mut self) -> io::Result<Option<u32>> {
-        self.with(|ref _component, ref mut section| Ok(section.last_id()))
+    /// Opens the section at the provided path for writing. If it's
+    /// created, and sequences are enabled, its items are numbered from
+    /// the provided sequence.
+    fn section_writer(&self, path: &PathBuf, first_sequence: u64) -> Result<SectionWriter> {
+        SectionWriter::new(
+            path,
+            self.max_file_size,
+            self.max_item_size,
+            self.read_chunk_size,
+            self.write_chunk_size,
+            self.checksums,
+            if self.sequences {
+                Some(first_sequence)
+            } else {
+                None
+            },
+        )
     }
 
-    pub fn sync(&mut self) -> io::Result<()> {
-        self.with(|ref _component, ref mut section| section.sync())
+    fn get(&self, path: &Path, offset: u64) -> Result<Option<OwnedQueueItem>> {
+        let (component, id) = offset_decode(offset)?;
+        let (_, section_path) = component.paths(path);
+
+        match SectionReader::get(&section_path, self.max_item_size, id) {
+            Ok(data) => Ok(data.map(|data| OwnedQueueItem { id: offset, data })),
+            Err(DepotError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(section_error(&component, e)),
+        }
     }
 
-    pub fn stream(&self, id: Option<u64>) -> io::Result<QueueStreamer> {
+    fn streamer(&self, path: &Path, id: Option<u64>) -> Result<QueueStreamer> {
         let (component, section_offset) = match id {
             Some(id) => offset_decode(id)?,
-            None => (Component::new(), 0),
+            None => (depot_next(path, None)?.unwrap_or_else(Component::new), 0),
         };
 
         // @FIXME have the struct take a reference equal to our lifetime?
         Ok(QueueStreamer::new(
-            self.path_buf.clone(),
+            path.to_path_buf(),
             component,
-            self.max_file_size,
-            self.max_item_size,
-            self.read_chunk_size,
+            self,
             section_offset,
         ))
     }
+}
 
-    fn advance(&mut self) -> io::Result<()> {
-        let max_file_size = self.max_file_size;
-        let max_item_size = self.max_item_size;
-        let read_chunk_size = self.read_chunk_size;
-        let write_chunk_size = self.write_chunk_size;
+impl Default for QueueOptions {
+    fn default() -> QueueOptions {
+        QueueOptions::new()
+    }
+}
 
-        let path_buf = self.path_buf.clone();
-        let next_component_section = self.with(|ref component, ref mut section| {
-            section.sync()?;
+pub struct Queue {
+    component_section: Option<(Component, SectionWriter)>,
+    lock: Option<File>,
+    notifier: Option<Arc<Notifier>>,
+    options: QueueOptions,
+    path_buf: PathBuf,
+    synced_at: Instant,
+    unsynced_bytes: u64,
+    unsynced_items: u64,
+}
 
-            match component.next() {
-                Some(c) => {
-                    // @TODO move the base path directly into components
-                    let (parent, path) = c.paths(&path_buf);
+impl Queue {
+    /// Constructs a new `Queue` that is used to read and
+    /// write items to the filesystem, with the default options.
+    ///
+    /// See `QueueOptions` to configure the queue.
+    pub fn new<S: AsRef<OsStr> + ?Sized>(path: &S) -> Queue {
+        QueueOptions::new().queue(PathBuf::from(path))
+    }
 
-                    fs::create_dir_all(&parent)?;
+    /// Appends an item to the queue, returning its id. This is the id
+    /// that readers see in `QueueItem`, and can be passed to `stream`.
+    ///
+    /// The item isn't visible to readers until it's flushed, and isn't
+    /// durable until it's synced, unless the sync policy does so.
+    pub fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let appended = self.with(|component, section| {
+            if section.is_full() {
+                Ok(None)
+            } else {
+                let id = section.append(data)?;
 
-                    let section = SectionWriter::new(
-                        &path,
-                        max_file_size,
-                        max_item_size,
-                        read_chunk_size,
-                        write_chunk_size,
-                    )?;
+                Ok(Some(offset_encode(component, id)))
+            }
+        })?;
 
-                    Ok((c, section))
-                }
+        let offset = match appended {
+            Some(offset) => offset,
 
-                None => Err(io::Error::new(io::ErrorKind::Other, "queue is full")),
+            None => {
+                self.advance()?;
+
+                self.with(|component2, section2| {
+                    let id = section2.append(data)?;
+
+                    Ok(offset_encode(component2, id))
+                })?
             }
-        })?;
+        };
 
-        self.component_section = Some(next_component_section);
+        self.appended(1, data.len() as u64)?;
 
-        Ok(())
+        Ok(offset)
     }
 
-    fn with<A, F>(&mut self, f: F) -> io::Result<A>
-    where
-        F: Fn(&Component, &mut SectionWriter) -> io::Result<A>,
-    {
-        if self.component_section.is_none() {
-            fs::create_dir_all(&self.path_buf)?;
+    /// Appends the provided items to the queue, returning the id that
+    /// each was given, in order.
+    ///
+    /// This is considerably faster than appending items individually,
+    /// as they're encoded together and written in chunks. The queue
+    /// moves on to new sections as needed, so the ids may span more
+    /// than one. If any item is too large, an error is returned before
+    /// the batch is written. For other errors, e.g. I/O, some of the
+    /// items may have been appended.
+    pub fn append_batch(&mut self, items: &[&[u8]]) -> Result<Vec<u64>> {
+        let mut advanced = false;
+        let mut offsets = Vec::with_capacity(items.len());
+        let mut remaining = items;
+
+        while !remaining.is_empty() {
+            let (component, ids) = self.with(|component, section| {
+                Ok((component.clone(), section.append_batch(remaining)?))
+            })?;
+
+            // validation leaves room for an item after the header of
+            // a new section, so one is appended to it at the least
+            if ids.is_empty() && advanced {
+                unreachable!("no items were appended to a new section");
+            }
 
-            let (c0_path, c0) = depot_latest_init_dir(&self.path_buf)?;
-            let (c1_path, c1) = depot_latest_init_dir(&c0_path)?;
-            let (c2_path, c2) = depot_latest_init_dir(&c1_path)?;
-            let (c3_path, c3) = depot_latest_init_file(&c2_path)?;
+            offsets.extend(ids.iter().map(|id| offset_encode(&component, *id)));
+            remaining = &remaining[ids.len()..];
 
-            self.component_section = Some((
-                Component::from(c0, c1, c2, c3)?,
-                SectionWriter::new(
-                    &c3_path,
-                    self.max_file_size,
-                    self.max_item_size,
-                    self.read_chunk_size,
-                    self.write_chunk_size,
-                )?,
-            ));
+            if !remaining.is_empty() {
+                self.advance()?;
+                advanced = true;
+            }
         }
 
-        match self.component_section {
-            Some((ref component, ref mut section)) => f(component, section),
+        let bytes = items.iter().map(|data| data.len() as u64).sum();
 
-            None => {
-                // this shouldn't be possible, given initialization above..
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "section not initialized; this is likely a bug",
-                ))
-            }
-        }
+        self.appended(items.len() as u64, bytes)?;
+
+        Ok(offsets)
     }
-}
 
-#[derive(Debug)]
-pub struct QueueItem<'a> {
-    pub id: u64,
-    pub data: &'a [u8],
-}
+    /// Updates the state used by the sync policy after items have been
+    /// appended, syncing if the policy requires it.
+    fn appended(&mut self, items: u64, bytes: u64) -> Result<()> {
+        self.unsynced_bytes += bytes;
+        self.unsynced_items += items;
+
+        let sync = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryAppend => true,
+            SyncPolicy::EveryNItems(n) => self.unsynced_items >= n,
+            SyncPolicy::EveryNBytes(n) => self.unsynced_bytes >= n,
+            SyncPolicy::Interval(d) => self.synced_at.elapsed() >= d,
+        };
 
-#[derive(Debug)]
-pub struct OwnedQueueItem {
-    pub id: u64,
-    pub data: Vec<u8>,
-}
+        if sync {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
 
-#[derive(Debug)]
-pub enum QueueItemType {
-    Regular,
-    Truncated,
-}
+    pub fn is_empty(&mut self) -> Result<bool> {
+        self.with(|component, section| Ok(component.is_empty() && section.is_empty()))
+    }
 
-pub struct QueueStreamer {
-    component: Component,
-    error: Option<io::Error>,
-    known_eof: bool,
-    max_file_size: u32,
-    max_item_size: u32,
-    path_buf: PathBuf,
-    read_chunk_size: u32,
-    section: Option<SectionStreamingIterator>,
-    section_offset: u32,
-}
+    pub fn is_full(&mut self) -> Result<bool> {
+        self.with(|component, section| Ok(component.is_full() && section.is_full()))
+    }
 
-impl QueueStreamer {
-    fn new(
-        path_buf: PathBuf,
-        component: Component,
-        max_file_size: u32,
-        max_item_size: u32,
-        read_chunk_size: u32,
-        section_offset: u32,
-    ) -> QueueStreamer {
-        QueueStreamer {
-            component,
-            error: None,
-            known_eof: false,
-            max_file_size,
-            max_item_size,
-            path_buf,
-            read_chunk_size,
-            section: None,
-            section_offset,
-        }
+    /// Returns the position of the last item within the current
+    /// section. This isn't an id that can be provided to `stream`;
+    /// for that, see `last_offset`.
+    pub fn last_id(&mut self) -> Result<Option<u32>> {
+        self.with(|_component, section| Ok(section.last_id()))
     }
 
-    /// Advances to the next item. If the next item is truncated and
-    /// include_truncated is false, it is skipped.
-    pub fn advance<'a>(&'a mut self, include_truncated: bool) {
-        loop {
-            // The last file we read indicated EOF, so we need
-            // to advance sections or bail out if unable to.
-            if self.known_eof {
-                match self.component.next() {
-                    Some(c) => {
-                        self.component = c;
-                        self.known_eof = false;
-                        self.section = None;
-                        self.section_offset = 0;
-                    }
+    /// Returns the id of the earliest item in the queue, i.e. the
+    /// first item that a streamer created via `stream(None)` reads,
+    /// or `None` if the queue has no items.
+    pub fn first_offset(&mut self) -> Result<Option<u64>> {
+        let current = self.with(|component, _section| Ok(component.clone()))?;
+        let mut next = depot_next(&self.path_buf, None)?;
 
-                    None => {
-                        return;
-                    }
-                }
+        while let Some(component) = next {
+            if component >= current {
+                break;
             }
 
-            // We haven't opened the next section yet, so attempt to.
-            // If it doesn't exist, we do nothing. If it does, attempt to
-            // open the file. If that fails, which should be rare, store
-            // the error.
-            if self.section.is_none() {
-                let (_, section_path) = self.component.paths(&self.path_buf);
+            let (_, path) = component.paths(&self.path_buf);
 
-                if section_path.exists() {
-                    let reader = SectionReader::new(
-                        section_path,
-                        self.max_file_size,
-                        self.max_item_size,
-                        self.read_chunk_size,
-                        Some(self.section_offset),
-                    );
-
-                    match reader {
-                        Ok(iterator) => {
-                            self.section = Some(iterator);
-                        }
+            let bounds = SectionReader::bounds(&path, self.options.read_chunk_size)
+                .map_err(|e| section_error(&component, e))?;
 
-                        Err(e) => {
-                            self.error = Some(e);
-                            return;
-                        }
-                    }
-                }
+            if let Some((first, _)) = bounds {
+                return Ok(Some(offset_encode(&component, first)));
             }
 
-            match self.section {
-                Some(ref mut s) => {
-                    s.advance();
+            next = depot_next(&self.path_buf, Some(&component))?;
+        }
 
-                    match s.current() {
-                        Ok(Some(ref item)) => {
-                            self.known_eof = item.known_eof;
-                            self.section_offset = item.id;
+        self.with(|component, section| {
+            Ok(section.first_id().map(|id| offset_encode(component, id)))
+        })
+    }
 
-                            if !item.truncated || include_truncated {
-                                return;
-                            }
+    /// Returns the id of the item that was most recently appended to
+    /// the queue, or `None` if the queue has no items.
+    pub fn last_offset(&mut self) -> Result<Option<u64>> {
+        let (current, last) =
+            self.with(|component, section| Ok((component.clone(), section.last_id())))?;
 
-                            // this is the only branch that continues
-                        }
+        if let Some(id) = last {
+            return Ok(Some(offset_encode(&current, id)));
+        }
 
-                        Ok(None) => {
-                            return;
-                        }
+        // The current section is empty, which is rare -- the queue is
+        // new, or we crashed directly after moving to a new section.
+        // Either way, the last item is in an earlier section, if any.
+        for (component, path) in depot_sections(&self.path_buf)?.iter().rev() {
+            if *component >= current {
+                continue;
+            }
 
-                        Err(e) => {
-                            self.error = Some(e);
-                            return;
-                        }
-                    }
-                }
+            let bounds = SectionReader::bounds(path, self.options.read_chunk_size)
+                .map_err(|e| section_error(component, e))?;
 
-                None => {
-                    return;
-                }
+            if let Some((_, last)) = bounds {
+                return Ok(Some(offset_encode(component, last)));
             }
         }
-    }
 
-    /// Returns the current element from the head.
-    pub fn current<'a>(&'a mut self) -> io::Result<Option<QueueItem<'a>>> {
-        self.current_all().map(|r| r.map(|(i, _)| i))
+        Ok(None)
     }
 
-    /// Returns the current element with its type.
-    pub fn current_all<'a>(&'a self) -> io::Result<Option<(QueueItem<'a>, QueueItemType)>> {
-        match self.error {
-            None => match self.section {
-                Some(ref s) => s.current().map(|m| {
-                    m.map(|i| {
-                        (
-                            QueueItem {
-                                id: offset_encode(&self.component, i.id),
-                                data: i.data,
-                            },
-                            if i.truncated {
-                                QueueItemType::Truncated
-                            } else {
-                                QueueItemType::Regular
-                            },
-                        )
-                    })
-                }),
-                None => Ok(None),
-            },
+    /// Returns the id that the next appended item will be given.
+    ///
+    /// The difference between this and the id of the last item a
+    /// consumer has processed is an indication of its lag, in bytes.
+    /// Note that ids skip ahead as the queue moves to a new section.
+    pub fn next_offset(&mut self) -> Result<u64> {
+        self.with(|component, section| {
+            if !section.is_full() {
+                return Ok(offset_encode(component, section.next_id()));
+            }
 
-            Some(ref e) => {
-                // @FIXME not sure to_string is the best here, but this
-                //        needs to clone arbitrary errors, so not sure
-                //        there is anything better
-                Err(io::Error::new(e.kind(), e.to_string()))
+            match component.next() {
+                Some(c) => Ok(offset_encode(&c, section::HEADER_SIZE as u32)),
+                None => Err(DepotError::QueueFull),
             }
-        }
+        })
     }
 
-    /// Returns the next item on the queue. This is the most common
-    /// way to iterate through a queue. This is a convenience for
-    /// advancing and returning the current item.
+    /// Returns the sequence that the next appended item will be given,
+    /// or `None` if the current section doesn't have sequences. See
+    /// `QueueOptions::sequences`.
     ///
-    /// Note that truncated items (due to crash/powerless) are skipped
-    /// over with this method.
-    pub fn next<'a>(&'a mut self) -> io::Result<Option<QueueItem<'a>>> {
-        self.advance(false);
-        self.current()
+    /// The difference between this and the sequence of the last item
+    /// a consumer has processed is the number of items it's behind.
+    pub fn next_sequence(&mut self) -> Result<Option<u64>> {
+        self.with(|_component, section| Ok(section.next_sequence()))
     }
 
-    /// Returns the next item on the queue, as well as its type.
+    /// Deletes the sections that precede the section containing the
+    /// provided offset, i.e. all items before it and possibly some
+    /// after it, returning the number of sections deleted. The
+    /// section that is currently being written to is never deleted.
     ///
-    /// This is a less commonly used operation but may be useful to know in certain
-    /// situations.
-    pub fn next_all<'a>(&'a mut self) -> io::Result<Option<(QueueItem<'a>, QueueItemType)>> {
-        self.advance(true);
-        self.current_all()
+    /// Directories that are left empty are also deleted. Readers that
+    /// have already opened a deleted section can continue to read it
+    /// on platforms that allow open files to be unlinked, e.g. Linux.
+    pub fn truncate_before(&mut self, offset: u64) -> Result<usize> {
+        let (component, _) = offset_decode(offset)?;
+        let current = self.with(|component, _section| Ok(component.clone()))?;
+        let limit = cmp::min(component, current);
+
+        let sections: Vec<(Component, PathBuf)> = depot_sections(&self.path_buf)?
+            .into_iter()
+            .filter(|(c, _)| *c < limit)
+            .collect();
+
+        remove_sections(&self.path_buf, &sections)?;
+
+        Ok(sections.len())
     }
 
-    /// Returns an `Iterator` over `OwnedQueueItem` structs. This
-    /// can be more convenient but requires an allocation of
-    /// a `Vec` for each item.
-    pub fn iter(self) -> impl Iterator<Item = io::Result<OwnedQueueItem>> {
-        QueueStreamerIterator { streamer: self }
+    /// Deletes the sections that fall outside of the configured
+    /// retention limits, returning the number of sections deleted.
+    ///
+    /// This is done automatically as the queue moves on to a new
+    /// section, but it may be useful to call this periodically if
+    /// the queue is written to infrequently. Unlike the automatic
+    /// enforcement, this returns any error that occurs.
+    pub fn enforce_retention(&mut self) -> Result<usize> {
+        let current = self.with(|component, _section| Ok(component.clone()))?;
+
+        if self.options.retention_max_age.is_none()
+            && self.options.retention_max_bytes.is_none()
+            && self.options.retention_max_sections.is_none()
+        {
+            return Ok(0);
+        }
+
+        let mut sections = depot_sections(&self.path_buf)?;
+        sections.retain(|(c, _)| *c <= current);
+
+        let now = SystemTime::now();
+        let mut kept = 0;
+        let mut kept_bytes = 0;
+
+        // Walk from the newest section to the oldest, keeping sections
+        // until one exceeds a limit. It and everything before it is
+        // deleted, so that the queue remains contiguous.
+        for (i, (component, path)) in sections.iter().enumerate().rev() {
+            // a section that has been deleted since the walk began
+            // doesn't count towards the limits
+            let meta = match fs::metadata(path) {
+                Ok(meta) => meta,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let index_len = match fs::metadata(section::index_path(path)) {
+                Ok(meta) => meta.len(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+
+            kept += 1;
+            kept_bytes += meta.len() + index_len;
+
+            if *component == current {
+                continue;
+            }
+
+            let expired = match self.options.retention_max_age {
+                Some(max_age) => now
+                    .duration_since(meta.modified()?)
+                    .map(|age| age > max_age)
+                    .unwrap_or(false),
+                None => false,
+            };
+
+            let too_large = match self.options.retention_max_bytes {
+                Some(max_bytes) => kept_bytes > max_bytes,
+                None => false,
+            };
+
+            let too_many = match self.options.retention_max_sections {
+                Some(max_sections) => kept > max_sections,
+                None => false,
+            };
+
+            if expired || too_large || too_many {
+                remove_sections(&self.path_buf, &sections[0..=i])?;
+
+                return Ok(i + 1);
+            }
+        }
+
+        Ok(0)
     }
-}
 
-struct QueueStreamerIterator {
-    streamer: QueueStreamer,
-}
+    /// Writes any buffered items to the filesystem, making them
+    /// visible to readers. This doesn't guarantee that they
+    /// survive power loss, see `sync`.
+    pub fn flush(&mut self) -> Result<()> {
+        self.with(|_component, section| section.flush())
+    }
 
-impl Iterator for QueueStreamerIterator {
-    type Item = io::Result<OwnedQueueItem>;
+    /// Writes any buffered items to the filesystem and waits for
+    /// them to reach the disk, making them durable.
+    ///
+    /// Sections that are full and the directories that contain
+    /// them are synced as the queue moves on to new sections, so
+    /// only the current section needs to be synced here.
+    pub fn sync(&mut self) -> Result<()> {
+        self.with(|_component, section| section.sync())?;
+        self.synced();
+        Ok(())
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.streamer.next() {
-            Ok(Some(item)) => Some(Ok(OwnedQueueItem {
-                id: item.id,
-                data: item.data.to_vec(),
-            })),
-            Ok(None) => None,
-            Err(e) => Some(Err(e)),
+    /// Returns the item with the provided id, or `None` if there's no
+    /// such item, e.g. because it hasn't been written yet or has been
+    /// deleted by retention.
+    ///
+    /// This is cheaper than streaming from the id when only one item
+    /// is needed. An id that doesn't refer to the start of an item is
+    /// invalid, and a truncated or corrupt item is reported as such.
+    pub fn get(&mut self, offset: u64) -> Result<Option<OwnedQueueItem>> {
+        let (component, _) = offset_decode(offset)?;
+
+        // the item may have been appended but not yet flushed
+        let flushed = match self.component_section {
+            Some((ref current, ref mut section)) if *current == component => {
+                let written = section.written();
+                section.flush()?;
+                section.written() != written
+            }
+
+            _ => false,
+        };
+
+        if flushed {
+            self.notify();
         }
+
+        self.options.get(&self.path_buf, offset)
     }
-}
 
-/// Extracts the number from a depot directory/file name.
-fn depot_number(name: &str) -> Option<u16> {
-    let len = name.len();
-    if name.starts_with("d") && len > 1 {
-        name[1..].parse().ok().and_then(|n| {
-            // @FIXME use Option#filter when in stable
-            if n <= MAX_COMPONENT_VALUE {
-                Some(n)
-            } else {
-                None
+    /// Creates a streamer that reads items from the provided id, or
+    /// from the earliest section that still exists if none is given.
+    ///
+    /// Sections that are missing, e.g. because they were deleted by
+    /// retention while the streamer was behind, are skipped.
+    pub fn stream(&self, id: Option<u64>) -> Result<QueueStreamer> {
+        self.options.streamer(&self.path_buf, id)
+    }
+
+    /// Creates a streamer for the named consumer, which resumes
+    /// after the last item that it committed, or at the earliest
+    /// item if it hasn't committed any.
+    ///
+    /// Offsets are stored in the queue's `consumers` directory.
+    pub fn consumer(&self, name: &str) -> Result<Consumer> {
+        let path_buf = consumer::consumer_path(&self.path_buf, name)?;
+        let committed = consumer::read_offset(&path_buf)?;
+        let streamer = self.stream(committed)?;
+
+        Ok(Consumer::new(name, path_buf, committed, streamer))
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        let options = self.options.clone();
+
+        let path_buf = self.path_buf.clone();
+        let next_component_section = self.with(|component, section| {
+            section.sync()?;
+
+            match component.next() {
+                Some(c) => {
+                    // @TODO move the base path directly into components
+                    let (parent, path) = c.paths(&path_buf);
+
+                    create_dir_all_synced(&parent)?;
+
+                    // the current section only lacks sequences if they
+                    // were enabled after it was created
+                    let first_sequence = match section.next_sequence() {
+                        Some(sequence) => sequence,
+                        None if options.sequences => {
+                            depot_next_sequence(&path_buf, &c, options.read_chunk_size)?
+                        }
+                        None => 0,
+                    };
+
+                    let section = options
+                        .section_writer(&path, first_sequence)
+                        .map_err(|e| section_error(&c, e))?;
+
+                    sync_dir(&parent)?;
+
+                    Ok((c, section))
+                }
+
+                None => Err(DepotError::QueueFull),
             }
-        })
-    } else {
-        None
+        })?;
+
+        self.component_section = Some(next_component_section);
+        self.synced();
+
+        // The items have been appended regardless, so a failure to
+        // delete old sections is left for the next attempt, or for
+        // an explicit call to `enforce_retention` to report.
+        let _ = self.enforce_retention();
+
+        Ok(())
     }
-}
 
-/// Finds the latest directory in the specified directory (not recursive). The
-/// path should already exist and be a directory.
-///
-/// If a directory does not exist, it should be created.
-///
-/// @TODO should we ensure that it's a directory if it already exists?
-fn depot_latest_init_dir<P: AsRef<Path>>(path: P) -> io::Result<(PathBuf, u16)> {
-    match depot_latest(&path)? {
-        Some((entry, n)) => Ok((entry.path(), n)),
+    /// Splits the queue into a writer, and a handle for reading that
+    /// can be cloned and shared with other threads.
+    ///
+    /// Streamers created via the handle are notified directly by the
+    /// writer when it flushes, rather than watching the filesystem.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use depot::Queue;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let (mut writer, reader) = Queue::new("/tmp/my-queue").split();
+    ///
+    /// thread::spawn(move || {
+    ///     let mut streamer = reader.stream(None).unwrap();
+    ///
+    ///     while let Some(item) = streamer.next_blocking(Duration::from_secs(60)).unwrap() {
+    ///         println!("{}: {} bytes", item.id, item.data.len());
+    ///     }
+    /// });
+    ///
+    /// writer.append(b"hello").unwrap();
+    /// writer.flush().unwrap();
+    /// ```
+    pub fn split(mut self) -> (QueueWriter, QueueReaderHandle) {
+        let notifier = Arc::new(Notifier::new());
+
+        let reader = QueueReader {
+            notifier: Some(notifier.clone()),
+            options: self.options.clone(),
+            path_buf: self.path_buf.clone(),
+        };
 
-        None => {
-            let path = path.as_ref().join(format!("d0"));
-            fs::create_dir(&path)?;
-            Ok((path, 0))
+        self.notifier = Some(notifier.clone());
+
+        (
+            QueueWriter { queue: self },
+            QueueReaderHandle {
+                notifier,
+                reader: Arc::new(reader),
+            },
+        )
+    }
+
+    /// Wakes readers created via `split`, after items were written to
+    /// the file.
+    fn notify(&self) {
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify();
         }
     }
-}
 
-/// Finds the latest file in the directory (not recursive). The path
-/// should already exist and be a directory.
-///
-/// Unlike the directory variant, if a file does not exist,
-/// its name is determined but it is not created. This is
-/// because the underlying section will create the file when
-/// it's opened.
-///
-/// @TODO should we ensure that it's NOT a directory if it already exists?
-fn depot_latest_init_file<P: AsRef<Path>>(path: P) -> io::Result<(PathBuf, u16)> {
-    match depot_latest(&path)? {
-        Some((entry, n)) => Ok((entry.path(), n)),
+    /// Resets the state used by the sync policy, after all items
+    /// appended so far have been synced.
+    fn synced(&mut self) {
+        self.synced_at = Instant::now();
+        self.unsynced_bytes = 0;
+        self.unsynced_items = 0;
+    }
 
-        None => {
-            let path = path.as_ref().join(format!("d0"));
-            Ok((path, 0))
+    /// Acquires the writer lock, which is held until the queue is
+    /// dropped, and records our pid in it for other writers to
+    /// report. The lock is advisory, via `flock` on Unix.
+    fn lock(&mut self, block: bool) -> Result<()> {
+        if self.lock.is_some() {
+            return Ok(());
         }
-    }
-}
 
-/// Finds the latest depot file or directory in a directory
-fn depot_latest<P: AsRef<Path>>(path: P) -> io::Result<Option<(DirEntry, u16)>> {
-    let paths = fs::read_dir(path)?;
+        create_dir_all_synced(&self.path_buf)?;
 
-    let mut max = None;
+        let path = self.path_buf.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
 
-    for entry in paths {
-        let entry = entry?;
+        if block {
+            file.lock()?;
+        } else {
+            match file.try_lock() {
+                Ok(()) => (),
 
-        if let Some(n) = entry.file_name().to_str().and_then(depot_number) {
-            match max {
-                Some((_, en)) if en > n => (),
-                _ => max = Some((entry, n)),
+                Err(TryLockError::WouldBlock) => {
+                    let pid = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|pid| pid.trim_end().parse().ok());
+
+                    return Err(DepotError::Locked { pid });
+                }
+
+                Err(TryLockError::Error(e)) => return Err(e.into()),
             }
         }
+
+        file.set_len(0)?;
+        file.write_all(format!("{}\n", process::id()).as_bytes())?;
+
+        self.lock = Some(file);
+
+        Ok(())
     }
 
-    Ok(max)
-}
+    fn with<A, F>(&mut self, f: F) -> Result<A>
+    where
+        F: Fn(&Component, &mut SectionWriter) -> Result<A>,
+    {
+        if self.component_section.is_none() {
+            self.lock(false)?;
 
-fn offset_encode(component: &Component, section_offset: u32) -> u64 {
-    let f = component.encode() as u64;
-    let s = section_offset as u64;
+            let (c0_path, c0) = depot_latest_init_dir(&self.path_buf)?;
+            let (c1_path, c1) = depot_latest_init_dir(&c0_path)?;
+            let (c2_path, c2) = depot_latest_init_dir(&c1_path)?;
+            let (c3_path, c3) = depot_latest_init_file(&c2_path)?;
 
-    (f << 32) + s
-}
+            let component = Component::from(c0, c1, c2, c3)?;
+            let created = !c3_path.exists();
+
+            // Only used if the section is created, or recreated after a
+            // crash while writing its header. Otherwise, it's read from
+            // the header, so the earlier sections needn't be scanned.
+            let first_sequence =
+                if self.options.sequences && SectionWriter::writes_header(&c3_path)? {
+                    depot_next_sequence(&self.path_buf, &component, self.options.read_chunk_size)?
+                } else {
+                    0
+                };
+
+            let section = self
+                .options
+                .section_writer(&c3_path, first_sequence)
+                .map_err(|e| section_error(&component, e))?;
+
+            if created {
+                sync_dir(&c2_path)?;
+            }
 
-fn offset_decode(offset: u64) -> io::Result<(Component, u32)> {
-    let f = (offset >> 32) as u32;
-    let s = (offset << 32 >> 32) as u32;
-    let c = Component::decode(f)?;
+            self.component_section = Some((component, section));
+        }
 
-    Ok((c, s))
-}
+        let (result, wrote) = match self.component_section {
+            Some((ref component, ref mut section)) => {
+                let written = section.written();
+                let result = f(component, section);
 
-#[cfg(test)]
-mod tests {
-    extern crate tempdir;
+                (result, section.written() != written)
+            }
 
-    use queue::*;
-    use std::path::PathBuf;
-    use std::thread;
-    use std::time;
+            None => {
+                // this shouldn't be possible, given initialization above..
+                return Err(
+                    io::Error::other("section not initialized; this is likely a bug").into(),
+                );
+            }
+        };
 
-    #[test]
-    fn test_component() {
-        let component = Component::new();
+        // Items reach the file when they're flushed or synced, but also
+        // when the write buffer fills up, or a section fills up and is
+        // synced, so readers are notified whenever any were written.
+        if wrote {
+            self.notify();
+        }
 
-        assert_eq!(component.encode(), 0);
+        result
+    }
+}
 
-        assert_eq!(Component::decode(0).unwrap(), component);
+/// A read-only handle to a queue, for inspecting and streaming its
+/// items without the ability to write to it.
+///
+/// Unlike `Queue`, this never creates, modifies or deletes anything
+/// on the filesystem, so it can be used with queues on read-only
+/// mounts or in backups. It doesn't take the writer lock, and can be
+/// used while another process writes to the queue.
+///
+/// # Examples
+///
+/// ```no_run
+/// use depot::QueueReader;
+///
+/// let reader = QueueReader::open("/tmp/my-queue").unwrap();
+///
+/// println!("last offset: {:?}", reader.last_offset().unwrap());
+///
+/// for item in reader.stream(None).unwrap().iter() {
+///     let item = item.unwrap();
+///     println!("{}: {} bytes", item.id, item.data.len());
+/// }
+/// ```
+pub struct QueueReader {
+    notifier: Option<Arc<Notifier>>,
+    options: QueueOptions,
+    path_buf: PathBuf,
+}
 
-        let component = component.next().unwrap();
+impl QueueReader {
+    /// Opens the queue at the provided path for reading, with the
+    /// default options. Fails if the queue doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<QueueReader> {
+        QueueOptions::new().open_reader(path)
+    }
 
-        assert_eq!(component.encode(), 1);
+    /// Returns the path of the queue.
+    pub fn path(&self) -> &Path {
+        &self.path_buf
+    }
 
-        assert_eq!(Component::decode(1).unwrap(), component);
+    /// Returns whether the queue has no items.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.first_offset()?.is_none())
+    }
 
-        let mut component = Component::new();
+    /// Returns the id of the earliest item in the queue, or `None` if
+    /// the queue has no items. See `Queue::first_offset`.
+    pub fn first_offset(&self) -> Result<Option<u64>> {
+        let mut next = depot_next(&self.path_buf, None)?;
 
-        for n in 0..10000 {
-            assert_eq!(component, Component::decode(n).unwrap());
+        while let Some(component) = next {
+            if let Some((first, _)) = self.bounds(&component)? {
+                return Ok(Some(offset_encode(&component, first)));
+            }
 
-            component = component.next().unwrap();
+            next = depot_next(&self.path_buf, Some(&component))?;
         }
 
-        assert_eq!(
-            Component::decode(1_999_999_999).unwrap(),
-            Component::from(1, 999, 999, 999).unwrap()
-        );
+        Ok(None)
+    }
 
-        assert!(Component::from(2, 0, 0, 0).is_err());
+    /// Returns the id of the last item that has been written to the
+    /// queue and flushed, or `None` if the queue has no items. See
+    /// `Queue::last_offset`.
+    pub fn last_offset(&self) -> Result<Option<u64>> {
+        for (component, _) in depot_sections(&self.path_buf)?.iter().rev() {
+            if let Some((_, last)) = self.bounds(component)? {
+                return Ok(Some(offset_encode(component, last)));
+            }
+        }
 
-        assert!(Component::decode(2_000_000_000).is_err());
+        Ok(None)
     }
 
-    #[test]
-    fn test_offset_encode_decode() {
-        let test = |component: Component, section_offset: u32, expected: u64| {
-            let encoded = offset_encode(&component, section_offset);
-            let decoded = offset_decode(encoded).unwrap();
+    /// Returns the item with the provided id, or `None` if there's no
+    /// such item. Only items that have been flushed can be read. See
+    /// `Queue::get`.
+    pub fn get(&self, offset: u64) -> Result<Option<OwnedQueueItem>> {
+        self.options.get(&self.path_buf, offset)
+    }
 
-            assert_eq!(encoded, expected);
+    /// Creates a streamer that reads items from the provided id, or
+    /// from the earliest section that still exists if none is given.
+    /// See `Queue::stream`.
+    pub fn stream(&self, id: Option<u64>) -> Result<QueueStreamer> {
+        let mut streamer = self.options.streamer(&self.path_buf, id)?;
+        streamer.notifier = self.notifier.clone();
+        Ok(streamer)
+    }
+
+    /// Returns the first and last ids of a section, treating one that
+    /// has been deleted, e.g. by retention, as having no items.
+    fn bounds(&self, component: &Component) -> Result<Option<(u32, u32)>> {
+        let (_, path) = component.paths(&self.path_buf);
+
+        match SectionReader::bounds(&path, self.options.read_chunk_size) {
+            Err(DepotError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            other => other.map_err(|e| section_error(component, e)),
+        }
+    }
+}
+
+/// The writing half of a queue that has been split, via `Queue::split`.
+///
+/// This provides all of the operations of `Queue`, and notifies the
+/// streamers created via its `QueueReaderHandle` when items it has
+/// appended are written to the file.
+pub struct QueueWriter {
+    queue: Queue,
+}
+
+impl Deref for QueueWriter {
+    type Target = Queue;
+
+    fn deref(&self) -> &Queue {
+        &self.queue
+    }
+}
+
+impl DerefMut for QueueWriter {
+    fn deref_mut(&mut self) -> &mut Queue {
+        &mut self.queue
+    }
+}
+
+/// The reading half of a queue that has been split, via `Queue::split`.
+///
+/// This provides all of the operations of `QueueReader`, and is cheap
+/// to clone, so that each thread can create its own streamers without
+/// coordinating with the writer.
+///
+/// Streamers created via the handle are woken by the writer when it
+/// flushes, rather than by watching the filesystem. Other threads can
+/// wait for flushes too, via `flushes` and `wait_for_flush`.
+#[derive(Clone)]
+pub struct QueueReaderHandle {
+    notifier: Arc<Notifier>,
+    reader: Arc<QueueReader>,
+}
+
+impl QueueReaderHandle {
+    /// Returns the number of times the writer has flushed, for use
+    /// with `wait_for_flush`. Syncs count as flushes, as do writes
+    /// that occur because the write buffer or a section filled up.
+    pub fn flushes(&self) -> u64 {
+        self.notifier.flushes()
+    }
+
+    /// Blocks until the writer has flushed more than the provided
+    /// number of times, or the timeout has elapsed, returning the
+    /// number of times it has flushed.
+    ///
+    /// The number should be obtained via `flushes` before checking
+    /// for new items, so that a flush in between isn't missed.
+    pub fn wait_for_flush(&self, flushes: u64, timeout: Duration) -> u64 {
+        self.notifier.wait(flushes, timeout)
+    }
+}
+
+impl Deref for QueueReaderHandle {
+    type Target = QueueReader;
+
+    fn deref(&self) -> &QueueReader {
+        &self.reader
+    }
+}
+
+#[derive(Debug)]
+pub struct QueueItem<'a> {
+    pub id: u64,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug)]
+pub struct OwnedQueueItem {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueItemType {
+    Regular,
+    Truncated,
+    Corrupt,
+}
+
+pub struct QueueStreamer {
+    component: Component,
+    error: Option<DepotError>,
+    known_eof: bool,
+    max_file_size: u32,
+    max_item_size: u32,
+    mmap: bool,
+    notifier: Option<Arc<Notifier>>,
+    path_buf: PathBuf,
+    read_chunk_size: u32,
+    resume_policy: ResumePolicy,
+    section: Option<SectionStreamingIterator>,
+    section_offset: u32,
+    watcher: Option<Watcher>,
+}
+
+impl QueueStreamer {
+    fn new(
+        path_buf: PathBuf,
+        component: Component,
+        options: &QueueOptions,
+        section_offset: u32,
+    ) -> QueueStreamer {
+        QueueStreamer {
+            component,
+            error: None,
+            known_eof: false,
+            max_file_size: options.max_file_size,
+            max_item_size: options.max_item_size,
+            mmap: options.mmap,
+            notifier: None,
+            path_buf,
+            read_chunk_size: options.read_chunk_size,
+            resume_policy: options.resume_policy,
+            section: None,
+            section_offset,
+            watcher: None,
+        }
+    }
+
+    /// Advances to the next item. If the next item is truncated and
+    /// include_truncated is false, it is skipped.
+    pub fn advance(&mut self, include_truncated: bool) {
+        loop {
+            // The last file we read indicated EOF, so we need
+            // to advance sections or bail out if unable to.
+            if self.known_eof {
+                match self.component.next() {
+                    Some(c) => {
+                        self.component = c;
+                        self.known_eof = false;
+                        self.section = None;
+                        self.section_offset = 0;
+                    }
+
+                    None => {
+                        return;
+                    }
+                }
+            }
+
+            // We haven't opened the next section yet, so attempt to.
+            // If it doesn't exist, we skip to the next one that does,
+            // if any, as it may have been removed. If it does, attempt
+            // to open the file. If that fails, which should be rare,
+            // store the error.
+            if self.section.is_none() {
+                let (_, section_path) = self.component.paths(&self.path_buf);
+
+                if !section_path.exists() {
+                    match depot_next(&self.path_buf, Some(&self.component)) {
+                        // The writer may have created the section, and
+                        // then the one we found, since we checked. They're
+                        // created in order, so check again now that a
+                        // later one exists, or we'd skip over it.
+                        Ok(Some(_)) if section_path.exists() => {
+                            continue;
+                        }
+
+                        Ok(Some(c)) => {
+                            self.component = c;
+                            self.section_offset = 0;
+                            continue;
+                        }
+
+                        Ok(None) => {
+                            return;
+                        }
+
+                        Err(e) => {
+                            self.error = Some(e.into());
+                            return;
+                        }
+                    }
+                }
+
+                let reader = SectionReader::new(
+                    section_path,
+                    self.max_file_size,
+                    self.max_item_size,
+                    self.read_chunk_size,
+                    Some(self.section_offset),
+                );
+
+                match reader {
+                    Ok(mut iterator) => {
+                        iterator.lenient(self.resume_policy == ResumePolicy::Lenient);
+
+                        // The writer only moves on once a section is
+                        // full, so it's sealed if a later one exists.
+                        // Mapping is an optimization, so if it fails,
+                        // e.g. due to the filesystem, the section is
+                        // read via a buffer instead.
+                        if self.mmap {
+                            if let Ok(Some(_)) = depot_next(&self.path_buf, Some(&self.component)) {
+                                let _ = iterator.map();
+                            }
+                        }
+
+                        self.section = Some(iterator);
+                    }
+
+                    // removed since we checked, so skip it next time around
+                    Err(DepotError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                        continue;
+                    }
+
+                    Err(e) => {
+                        self.error = Some(section_error(&self.component, e));
+                        return;
+                    }
+                }
+            }
+
+            match self.section {
+                Some(ref mut s) => {
+                    s.advance();
+
+                    match s.current() {
+                        Ok(Some(ref item)) => {
+                            self.known_eof = item.known_eof;
+                            self.section_offset = item.id;
+
+                            if !item.truncated || include_truncated {
+                                return;
+                            }
+
+                            // this is the only branch that continues
+                        }
+
+                        Ok(None) => {
+                            return;
+                        }
+
+                        Err(e) => {
+                            self.error = Some(section_error(&self.component, e));
+                            return;
+                        }
+                    }
+                }
+
+                None => {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns the current element from the head.
+    ///
+    /// If the element's checksum doesn't match its data, an error
+    /// is returned instead. Subsequent calls to `advance` will move
+    /// past it.
+    pub fn current(&mut self) -> Result<Option<QueueItem<'_>>> {
+        match self.current_all()? {
+            Some((item, QueueItemType::Corrupt)) => {
+                Err(DepotError::corrupt(item.id, "checksum mismatch"))
+            }
+
+            other => Ok(other.map(|(i, _)| i)),
+        }
+    }
+
+    /// Returns the current element with its type.
+    pub fn current_all<'a>(&'a self) -> Result<Option<(QueueItem<'a>, QueueItemType)>> {
+        match self.error {
+            None => match self.section {
+                Some(ref s) => s.current().map(|m| {
+                    m.map(|i| {
+                        (
+                            QueueItem {
+                                id: offset_encode(&self.component, i.id),
+                                data: i.data,
+                            },
+                            if i.truncated {
+                                QueueItemType::Truncated
+                            } else if i.corrupt {
+                                QueueItemType::Corrupt
+                            } else {
+                                QueueItemType::Regular
+                            },
+                        )
+                    })
+                }),
+                None => Ok(None),
+            },
+
+            Some(ref e) => Err(e.duplicate()),
+        }
+    }
+
+    /// Returns the next item on the queue. This is the most common
+    /// way to iterate through a queue. This is a convenience for
+    /// advancing and returning the current item.
+    ///
+    /// Note that truncated items (due to crash/powerless) are skipped
+    /// over with this method.
+    pub fn next<'a>(&'a mut self) -> Result<Option<QueueItem<'a>>> {
+        self.advance(false);
+        self.current()
+    }
+
+    /// Returns the next item on the queue, as well as its type.
+    ///
+    /// This is a less commonly used operation but may be useful to know in certain
+    /// situations.
+    pub fn next_all<'a>(&'a mut self) -> Result<Option<(QueueItem<'a>, QueueItemType)>> {
+        self.advance(true);
+        self.current_all()
+    }
+
+    /// Returns the next item on the queue, waiting up to the provided
+    /// timeout for one to be available, or `None` if none was.
+    ///
+    /// Items only become available once the writer has flushed them,
+    /// e.g. via `Queue::flush` or `Queue::sync`, or its write buffer
+    /// has filled up. Streamers created via
+    /// a `QueueReaderHandle` are woken by the writer directly. Others
+    /// watch for changes via inotify on Linux, so they wake up
+    /// promptly, and poll the queue elsewhere.
+    pub fn next_blocking<'a>(&'a mut self, timeout: Duration) -> Result<Option<QueueItem<'a>>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            self.advance(false);
+
+            if !matches!(self.current_all(), Ok(None)) {
+                break;
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                break;
+            }
+
+            if let Some(notifier) = self.notifier.clone() {
+                // As with the watcher below, check again once the
+                // count has been read, so that a flush isn't missed.
+                let flushes = notifier.flushes();
+                self.advance(false);
+
+                if !matches!(self.current_all(), Ok(None)) {
+                    break;
+                }
+
+                notifier.wait(flushes, deadline - now);
+
+                continue;
+            }
+
+            let mut watcher = match self.watcher.take() {
+                Some(watcher) => watcher,
+                None => Watcher::new()?,
+            };
+
+            // The section's directory and its ancestors are watched
+            // too, as that's where the next section (or directories
+            // for it) will be created.
+            {
+                let (parent, section_path) = self.component.paths(&self.path_buf);
+                let dirs: Vec<&Path> = parent.ancestors().take(4).collect();
+
+                watcher.watch(&section_path, &dirs);
+            }
+
+            // Check again now that changes are being watched, as any
+            // made since we last checked would otherwise be missed.
+            self.advance(false);
+
+            let available = !matches!(self.current_all(), Ok(None));
+
+            if !available {
+                watcher.wait(deadline - now)?;
+            }
+
+            self.watcher = Some(watcher);
+
+            if available {
+                break;
+            }
+        }
+
+        self.current()
+    }
+
+    /// Returns the sequence of the current item, if the queue has
+    /// sequences. See `QueueOptions::sequences`.
+    ///
+    /// Items in sections created without sequences don't have one,
+    /// nor do truncated items. If a section's index is missing, the
+    /// sequences of its items can't be determined either.
+    pub fn sequence(&self) -> Option<u64> {
+        match (&self.error, &self.section) {
+            (None, Some(s)) => match s.current() {
+                Ok(Some(item)) => item.sequence,
+                _ => None,
+            },
+
+            _ => None,
+        }
+    }
+
+    /// Repositions the streamer so that the next item it returns is
+    /// the one with the provided sequence. Returns false, leaving the
+    /// streamer where it was, if there's no such item, e.g. because it
+    /// hasn't been written yet or has been deleted by retention.
+    ///
+    /// The section containing the item is found via the headers of
+    /// the sections, newest first, and the item via its index.
+    pub fn seek_to_sequence(&mut self, sequence: u64) -> Result<bool> {
+        for (component, path) in depot_sections(&self.path_buf)?.iter().rev() {
+            let first = match SectionReader::sequences(path) {
+                Ok(Some((first, _))) if first <= sequence => first,
+
+                // removed since we listed it, e.g. by retention
+                Err(DepotError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => continue,
+
+                Err(e) => return Err(section_error(component, e)),
+
+                Ok(_) => continue,
+            };
+
+            let id = match SectionReader::nth_id(path, sequence - first) {
+                Ok(Some(id)) => id,
+                Ok(None) => return Ok(false),
+                Err(e) => return Err(section_error(component, e)),
+            };
+
+            self.component = component.clone();
+            self.error = None;
+            self.known_eof = false;
+            self.section = None;
+            self.section_offset = id;
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Returns an `Iterator` over `OwnedQueueItem` structs. This
+    /// can be more convenient but requires an allocation of
+    /// a `Vec` for each item.
+    pub fn iter(self) -> impl Iterator<Item = Result<OwnedQueueItem>> {
+        QueueStreamerIterator { streamer: self }
+    }
+}
+
+struct QueueStreamerIterator {
+    streamer: QueueStreamer,
+}
+
+impl Iterator for QueueStreamerIterator {
+    type Item = Result<OwnedQueueItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.streamer.next() {
+            Ok(Some(item)) => Some(Ok(OwnedQueueItem {
+                id: item.id,
+                data: item.data.to_vec(),
+            })),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Extracts the number from a depot directory/file name.
+fn depot_number(name: &str) -> Option<u16> {
+    let len = name.len();
+    if name.starts_with("d") && len > 1 {
+        name[1..].parse().ok().and_then(|n| {
+            // @FIXME use Option#filter when in stable
+            if n <= MAX_COMPONENT_VALUE {
+                Some(n)
+            } else {
+                None
+            }
+        })
+    } else {
+        None
+    }
+}
+
+/// Finds the latest directory in the specified directory (not recursive). The
+/// path should already exist and be a directory.
+///
+/// If a directory does not exist, it should be created.
+///
+/// @TODO should we ensure that it's a directory if it already exists?
+fn depot_latest_init_dir<P: AsRef<Path>>(path: P) -> io::Result<(PathBuf, u16)> {
+    match depot_latest(&path)? {
+        Some((entry, n)) => Ok((entry.path(), n)),
+
+        None => {
+            let dir_path = path.as_ref().join("d0");
+            fs::create_dir(&dir_path)?;
+            sync_dir(path.as_ref())?;
+            Ok((dir_path, 0))
+        }
+    }
+}
+
+/// Finds the latest file in the directory (not recursive). The path
+/// should already exist and be a directory.
+///
+/// Unlike the directory variant, if a file does not exist,
+/// its name is determined but it is not created. This is
+/// because the underlying section will create the file when
+/// it's opened.
+///
+/// @TODO should we ensure that it's NOT a directory if it already exists?
+fn depot_latest_init_file<P: AsRef<Path>>(path: P) -> io::Result<(PathBuf, u16)> {
+    match depot_latest(&path)? {
+        Some((entry, n)) => Ok((entry.path(), n)),
+
+        None => {
+            let path = path.as_ref().join("d0");
+            Ok((path, 0))
+        }
+    }
+}
+
+/// Lists the depot files or directories in a directory, in order.
+fn depot_entries<P: AsRef<Path>>(path: P) -> io::Result<Vec<(u16, PathBuf)>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+
+        if let Some(n) = entry.file_name().to_str().and_then(depot_number) {
+            entries.push((n, entry.path()));
+        }
+    }
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+/// Lists the depot files or directories in a directory, in order, or
+/// nothing if the directory doesn't exist.
+fn depot_entries_if_exists<P: AsRef<Path>>(path: P) -> io::Result<Vec<(u16, PathBuf)>> {
+    match depot_entries(path) {
+        Ok(entries) => Ok(entries),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Lists the sections of the queue at the provided path, in order. If
+/// the path doesn't exist, the queue is considered to be empty.
+///
+/// Directories may be removed concurrently by retention, in which
+/// case they're considered to be empty.
+fn depot_sections<P: AsRef<Path>>(path: P) -> io::Result<Vec<(Component, PathBuf)>> {
+    let mut sections = Vec::new();
+
+    for (c0, c0_path) in depot_entries_if_exists(path)? {
+        for (c1, c1_path) in depot_entries_if_exists(c0_path)? {
+            for (c2, c2_path) in depot_entries_if_exists(c1_path)? {
+                for (c3, c3_path) in depot_entries_if_exists(c2_path)? {
+                    if let Ok(component) = Component::from(c0, c1, c2, c3) {
+                        sections.push((component, c3_path));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(sections)
+}
+
+/// Finds the first section of the queue at the provided path whose
+/// component comes after the provided one, or the first section of
+/// all if none is provided.
+///
+/// Unlike `depot_sections`, directories that can only contain
+/// earlier sections aren't visited, so this is cheap to call when
+/// tailing a large queue.
+fn depot_next<P: AsRef<Path>>(path: P, after: Option<&Component>) -> io::Result<Option<Component>> {
+    let bound = after.map(|c| [c.one, c.two, c.three, c.four]);
+
+    depot_next_level(path.as_ref(), &mut Vec::with_capacity(4), bound.as_ref())
+}
+
+/// Searches one level of the directory tree for `depot_next`. The
+/// bound is only provided while the prefix is equal to it, as once a
+/// greater entry has been visited everything below it qualifies.
+fn depot_next_level(
+    path: &Path,
+    prefix: &mut Vec<u16>,
+    bound: Option<&[u16; 4]>,
+) -> io::Result<Option<Component>> {
+    let level = prefix.len();
+
+    // directories may be removed concurrently by retention
+    let entries = match depot_entries(path) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    for (n, entry_path) in entries {
+        let next_bound = match bound {
+            Some(b) if n < b[level] => continue,
+            Some(b) if n == b[level] => Some(b),
+            _ => None,
+        };
+
+        if level == 3 {
+            if next_bound.is_none() {
+                if let Ok(component) = Component::from(prefix[0], prefix[1], prefix[2], n) {
+                    return Ok(Some(component));
+                }
+            }
+        } else {
+            prefix.push(n);
+            let found = depot_next_level(&entry_path, prefix, next_bound)?;
+            prefix.pop();
+
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Deletes the provided sections of the queue at the provided path,
+/// and then any of their directories that are left empty.
+fn remove_sections<P: AsRef<Path>>(path: P, sections: &[(Component, PathBuf)]) -> io::Result<()> {
+    for (_, section_path) in sections {
+        // the index is removed first, so that it isn't left behind
+        match fs::remove_file(section::index_path(section_path)) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        match fs::remove_file(section_path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    // directories are visited deepest first, so that removing a
+    // directory can leave its parent empty
+    for (_, section_path) in sections.iter().rev() {
+        let mut dir = section_path.parent();
+
+        while let Some(d) = dir {
+            if d == path.as_ref() || !d.exists() || fs::read_dir(d)?.next().is_some() {
+                break;
+            }
+
+            fs::remove_dir(d)?;
+            dir = d.parent();
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the sequence that a section created after those before the
+/// provided component starts at, i.e. the sequence that follows the
+/// items of the latest of them.
+///
+/// Sections without sequences, e.g. written while they were disabled,
+/// are counted as if they had them, so that sequences continue from
+/// the latest section that does. If none do, items are counted from
+/// the earliest section.
+fn depot_next_sequence<P: AsRef<Path>>(
+    path: P,
+    before: &Component,
+    read_chunk_size: u32,
+) -> Result<u64> {
+    let mut items = 0;
+
+    for (component, section_path) in depot_sections(path)?.iter().rev() {
+        if component >= before {
+            continue;
+        }
+
+        let sequences =
+            SectionReader::sequences(section_path).map_err(|e| section_error(component, e))?;
+
+        if let Some((first, indexed)) = sequences {
+            return Ok(first + indexed + items);
+        }
+
+        items += SectionReader::items(section_path, read_chunk_size)
+            .map_err(|e| section_error(component, e))?;
+    }
+
+    Ok(items)
+}
+
+/// Finds the latest depot file or directory in a directory
+fn depot_latest<P: AsRef<Path>>(path: P) -> io::Result<Option<(DirEntry, u16)>> {
+    let paths = fs::read_dir(path)?;
+
+    let mut max = None;
+
+    for entry in paths {
+        let entry = entry?;
+
+        if let Some(n) = entry.file_name().to_str().and_then(depot_number) {
+            match max {
+                Some((_, en)) if en > n => (),
+                _ => max = Some((entry, n)),
+            }
+        }
+    }
+
+    Ok(max)
+}
+
+/// Creates a directory and any of its missing parents, like
+/// `fs::create_dir_all`, but also syncs the parent of each
+/// directory that is created so that they survive power loss.
+pub(crate) fn create_dir_all_synced(path: &Path) -> io::Result<()> {
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Ok(());
+    }
+
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+
+    if let Some(parent) = parent {
+        create_dir_all_synced(parent)?;
+    }
+
+    match fs::create_dir(path) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    sync_dir(parent.unwrap_or_else(|| Path::new(".")))
+}
+
+/// Syncs a directory's entries to disk, which is required for
+/// the files and directories created in it to survive power
+/// loss.
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// Directories can't be opened (and synced) on this platform,
+/// so this is a no-op.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Converts the offsets in errors from a section, which are positions
+/// within it, to offsets of the queue.
+fn section_error(component: &Component, e: DepotError) -> DepotError {
+    match e {
+        DepotError::Corrupt { offset, reason } => DepotError::Corrupt {
+            offset: offset_encode(component, offset as u32),
+            reason,
+        },
+
+        DepotError::InvalidOffset(offset) => {
+            DepotError::InvalidOffset(offset_encode(component, offset as u32))
+        }
+
+        other => other,
+    }
+}
+
+fn offset_encode(component: &Component, section_offset: u32) -> u64 {
+    let f = component.encode() as u64;
+    let s = section_offset as u64;
+
+    (f << 32) + s
+}
+
+fn offset_decode(offset: u64) -> Result<(Component, u32)> {
+    let f = (offset >> 32) as u32;
+    let s = (offset << 32 >> 32) as u32;
+    let c = Component::decode(f).map_err(|_| DepotError::InvalidOffset(offset))?;
+
+    Ok((c, s))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate tempdir;
+
+    use queue::*;
+    use std::thread;
+    use std::time;
+
+    /// Returns options for a queue whose sections only fit a few small
+    /// items, so that tests span many sections with little data.
+    pub(crate) fn small_options() -> QueueOptions {
+        QueueOptions::new()
+            .max_file_size(48)
+            .max_item_size(16)
+            .clone()
+    }
+
+    /// Opens a queue with small sections and appends `n` items to it,
+    /// "item 0", "item 1", ..., returning the queue and the ids of the
+    /// items. They're flushed, so readers can see them.
+    pub(crate) fn small_queue(path: &Path, n: usize) -> (Queue, Vec<u64>) {
+        let mut queue = small_options().open(path).unwrap();
+
+        let ids = (0..n)
+            .map(|i| queue.append(format!("item {}", i).as_bytes()).unwrap())
+            .collect();
+
+        queue.flush().unwrap();
+
+        (queue, ids)
+    }
+
+    /// Reads items from a streamer until it has no more.
+    fn read_items(streamer: QueueStreamer) -> Vec<OwnedQueueItem> {
+        streamer.iter().map(|item| item.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_component() {
+        let component = Component::new();
+
+        assert_eq!(component.encode(), 0);
+
+        assert_eq!(Component::decode(0).unwrap(), component);
+
+        let component = component.next().unwrap();
+
+        assert_eq!(component.encode(), 1);
+
+        assert_eq!(Component::decode(1).unwrap(), component);
+
+        let mut component = Component::new();
+
+        for n in 0..10000 {
+            assert_eq!(component, Component::decode(n).unwrap());
+
+            component = component.next().unwrap();
+        }
+
+        assert_eq!(
+            Component::decode(1_999_999_999).unwrap(),
+            Component::from(1, 999, 999, 999).unwrap()
+        );
+
+        assert!(Component::from(2, 0, 0, 0).is_err());
+
+        assert!(Component::decode(2_000_000_000).is_err());
+    }
+
+    #[test]
+    fn test_offset_encode_decode() {
+        let test = |component: Component, section_offset: u32, expected: u64| {
+            let encoded = offset_encode(&component, section_offset);
+            let decoded = offset_decode(encoded).unwrap();
+
+            assert_eq!(encoded, expected);
             assert_eq!((component, section_offset), decoded);
         };
 
-        test(Component::new(), 0, 0);
-        test(Component::new(), 1, 1);
-        test(Component::from(0, 0, 0, 1).unwrap(), 0, 1 << 32);
-        test(Component::from(0, 0, 0, 2).unwrap(), 1, (1 << 33) + 1);
-        test(
-            Component::from(1, 999, 999, 999).unwrap(),
-            1,
-            8589934587705032705,
+        test(Component::new(), 0, 0);
+        test(Component::new(), 1, 1);
+        test(Component::from(0, 0, 0, 1).unwrap(), 0, 1 << 32);
+        test(Component::from(0, 0, 0, 2).unwrap(), 1, (1 << 33) + 1);
+        test(
+            Component::from(1, 999, 999, 999).unwrap(),
+            1,
+            8589934587705032705,
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path();
+
+        assert!(QueueOptions::new().open(path).is_ok());
+        assert!(QueueOptions::new().max_item_size(0).open(path).is_err());
+        assert!(QueueOptions::new()
+            .max_item_size(section::MAX_ITEM_SIZE + 1)
+            .open(path)
+            .is_err());
+        assert!(QueueOptions::new().max_file_size(0).open(path).is_err());
+
+        // sections must have room for an item after their header
+        match QueueOptions::new()
+            .max_file_size(16)
+            .max_item_size(8)
+            .open(path)
+        {
+            Err(DepotError::InvalidConfig(_)) => (),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+        assert!(QueueOptions::new()
+            .max_file_size(section::MIN_FILE_SIZE - 1)
+            .open(path)
+            .is_err());
+
+        let other_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let mut queue = QueueOptions::new()
+            .max_file_size(section::MIN_FILE_SIZE)
+            .open(other_dir.path())
+            .unwrap();
+        for i in 0..3 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(depot_sections(other_dir.path()).unwrap().len(), 3);
+        assert!(QueueOptions::new()
+            .max_file_size(2147483647)
+            .open(path)
+            .is_err());
+        assert!(QueueOptions::new()
+            .max_item_size(section::MAX_ITEM_SIZE)
+            .max_file_size(2147287039)
+            .open(path)
+            .is_err());
+        assert!(QueueOptions::new().read_chunk_size(0).open(path).is_err());
+        assert!(QueueOptions::new().write_chunk_size(0).open(path).is_err());
+
+        let file_path = path.join("file");
+        fs::write(&file_path, b"").unwrap();
+        assert!(QueueOptions::new().open(&file_path).is_err());
+    }
+
+    #[test]
+    fn test_small_sections() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let size = 2500;
+
+        let mut queue = small_options()
+            .read_chunk_size(16)
+            .write_chunk_size(16)
+            .open(tmp_dir.path())
+            .unwrap();
+
+        for i in 0..size {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+
+        queue.sync().unwrap();
+
+        let (_, path) = Component::from(0, 0, 1, 0).unwrap().paths(tmp_dir.path());
+        assert!(path.exists());
+
+        let items = read_items(queue.stream(None).unwrap());
+        assert_eq!(items.len(), size);
+
+        for (i, item) in items.iter().enumerate() {
+            assert_eq!(item.data, format!("item {}", i).as_bytes());
+
+            if i > 0 {
+                assert!(item.id > items[i - 1].id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncate_before() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let (mut queue, ids) = small_queue(tmp_dir.path(), 2500);
+
+        let mut reader = queue.stream(None).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"item 0");
+
+        let target = offset_encode(&Component::from(0, 0, 1, 5).unwrap(), 0);
+        assert_eq!(queue.truncate_before(target).unwrap(), 1005);
+        assert_eq!(queue.truncate_before(target).unwrap(), 0);
+
+        let (parent, _) = Component::new().paths(tmp_dir.path());
+        assert!(!parent.exists());
+        assert!(parent.parent().unwrap().exists());
+
+        // an open section can still be read after it is deleted
+        #[cfg(unix)]
+        assert_eq!(reader.next().unwrap().unwrap().data, b"item 1");
+
+        let remaining: Vec<&u64> = ids.iter().filter(|id| **id >= target).collect();
+        let items = read_items(queue.stream(Some(*remaining[0])).unwrap());
+        assert_eq!(items.len(), remaining.len());
+        assert_eq!(items[0].id, *remaining[0]);
+
+        // the current section is never deleted
+        let sections = depot_sections(tmp_dir.path()).unwrap().len();
+        assert_eq!(queue.truncate_before(u64::MAX >> 2).unwrap(), sections - 1);
+        assert_eq!(depot_sections(tmp_dir.path()).unwrap().len(), 1);
+        queue.append(b"last").unwrap();
+    }
+
+    #[test]
+    fn test_sections_while_removing() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let parents: Vec<PathBuf> = (0..500)
+            .map(|three| {
+                let (parent, file) = Component::from(0, 0, three, 0)
+                    .unwrap()
+                    .paths(tmp_dir.path());
+                fs::create_dir_all(&parent).unwrap();
+                fs::File::create(file).unwrap();
+                parent
+            })
+            .collect();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let lister = {
+            let done = done.clone();
+            let path = tmp_dir.path().to_path_buf();
+
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    depot_sections(&path).unwrap();
+                }
+            })
+        };
+
+        // directories removed while they're being listed are skipped
+        for parent in parents {
+            fs::remove_dir_all(parent).unwrap();
+        }
+
+        done.store(true, Ordering::SeqCst);
+        lister.join().unwrap();
+        assert!(depot_sections(tmp_dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sequences() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let options = small_options().sequences(true).clone();
+
+        let mut queue = options.open(tmp_dir.path()).unwrap();
+        assert_eq!(queue.next_sequence().unwrap(), Some(0));
+
+        let mut ids = Vec::new();
+
+        for i in 0..25 {
+            ids.push(queue.append(format!("item {}", i).as_bytes()).unwrap());
+        }
+
+        ids.extend(
+            queue
+                .append_batch(&[b"item 25", b"item 26", b"item 27"])
+                .unwrap(),
+        );
+
+        queue.sync().unwrap();
+        assert_eq!(queue.next_sequence().unwrap(), Some(28));
+
+        let mapped = options
+            .clone()
+            .mmap(true)
+            .open_reader(tmp_dir.path())
+            .unwrap();
+
+        for mut streamer in [queue.stream(None).unwrap(), mapped.stream(None).unwrap()] {
+            for (i, id) in ids.iter().enumerate() {
+                assert_eq!(streamer.next().unwrap().unwrap().id, *id);
+                assert_eq!(streamer.sequence(), Some(i as u64));
+            }
+        }
+
+        // streams that resume mid-queue know the sequence too
+        let mut streamer = queue.stream(Some(ids[13])).unwrap();
+        streamer.next().unwrap();
+        assert_eq!(streamer.sequence(), Some(13));
+
+        for &n in &[0, 1, 7, 13, 27, 12] {
+            assert!(streamer.seek_to_sequence(n).unwrap());
+
+            let item = streamer.next().unwrap().unwrap();
+            assert_eq!(item.id, ids[n as usize]);
+            assert_eq!(item.data, format!("item {}", n).as_bytes());
+            assert_eq!(streamer.sequence(), Some(n));
+        }
+
+        // there's no such item yet, so the streamer stays where it was
+        assert!(!streamer.seek_to_sequence(28).unwrap());
+        assert_eq!(streamer.next().unwrap().unwrap().id, ids[13]);
+
+        // sequences continue after reopening
+        drop(queue);
+        let mut queue = options.open(tmp_dir.path()).unwrap();
+        assert_eq!(queue.next_sequence().unwrap(), Some(28));
+        ids.push(queue.append(b"item 28").unwrap());
+        queue.flush().unwrap();
+
+        assert!(streamer.seek_to_sequence(28).unwrap());
+        assert_eq!(streamer.next().unwrap().unwrap().id, ids[28]);
+
+        // indexes are deleted along with their sections
+        let (_, first_path) = Component::new().paths(tmp_dir.path());
+        assert!(section::index_path(&first_path).exists());
+        assert!(queue.truncate_before(ids[20]).unwrap() > 0);
+        assert!(!section::index_path(&first_path).exists());
+        assert!(!streamer.seek_to_sequence(0).unwrap());
+        assert!(streamer.seek_to_sequence(20).unwrap());
+        assert_eq!(streamer.next().unwrap().unwrap().id, ids[20]);
+
+        // without sequences, items don't have them
+        let other_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let mut queue = Queue::new(other_dir.path());
+        queue.append(b"item").unwrap();
+        queue.flush().unwrap();
+
+        let mut streamer = queue.stream(None).unwrap();
+        streamer.next().unwrap();
+        assert_eq!(queue.next_sequence().unwrap(), None);
+        assert_eq!(streamer.sequence(), None);
+        assert!(!streamer.seek_to_sequence(0).unwrap());
+    }
+
+    #[test]
+    fn test_sequences_enabled_later() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let mut ids = Vec::new();
+
+        // sequences are enabled for an existing queue, disabled again
+        // and then re-enabled, each time for a few sections
+        for &sequences in &[false, true, false, true] {
+            let mut queue = small_options()
+                .sequences(sequences)
+                .open(tmp_dir.path())
+                .unwrap();
+
+            for _ in 0..5 {
+                let i = ids.len();
+                ids.push(queue.append(format!("item {}", i).as_bytes()).unwrap());
+            }
+
+            queue.sync().unwrap();
+        }
+
+        let mut queue = QueueOptions::new()
+            .sequences(true)
+            .open(tmp_dir.path())
+            .unwrap();
+        assert_eq!(queue.next_sequence().unwrap(), Some(20));
+
+        // items have either no sequence, or the one that counts them
+        let mut streamer = queue.stream(None).unwrap();
+        let mut sequenced = 0;
+
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(streamer.next().unwrap().unwrap().id, *id);
+
+            if let Some(sequence) = streamer.sequence() {
+                assert_eq!(sequence, i as u64);
+                sequenced += 1;
+            }
+        }
+
+        assert!(sequenced >= 4);
+        assert!(streamer.seek_to_sequence(19).unwrap());
+        assert_eq!(streamer.next().unwrap().unwrap().id, ids[19]);
+    }
+
+    #[test]
+    fn test_seek_while_truncating() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let options = small_options().sequences(true).clone();
+        let mut queue = options.open(tmp_dir.path()).unwrap();
+
+        for i in 0..2500 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+
+        queue.flush().unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let seeker = {
+            let done = done.clone();
+            let reader = options.open_reader(tmp_dir.path()).unwrap();
+
+            thread::spawn(move || {
+                let mut streamer = reader.stream(None).unwrap();
+
+                while !done.load(Ordering::SeqCst) {
+                    // may or may not have been deleted yet
+                    streamer.seek_to_sequence(1500).unwrap();
+
+                    assert!(streamer.seek_to_sequence(2499).unwrap());
+                    assert_eq!(streamer.next().unwrap().unwrap().data, b"item 2499");
+                }
+            })
+        };
+
+        for three in 0..2 {
+            for four in (0..1000).step_by(50) {
+                let component = Component::from(0, 0, three, four).unwrap();
+                queue.truncate_before(offset_encode(&component, 0)).unwrap();
+            }
+        }
+
+        done.store(true, Ordering::SeqCst);
+        seeker.join().unwrap();
+    }
+
+    #[test]
+    fn test_offsets() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let open = || small_options().open(tmp_dir.path()).unwrap();
+
+        let mut queue = open();
+        let start = offset_encode(&Component::new(), section::HEADER_SIZE as u32);
+
+        assert_eq!(queue.first_offset().unwrap(), None);
+        assert_eq!(queue.last_offset().unwrap(), None);
+        assert_eq!(queue.next_offset().unwrap(), start);
+
+        let mut expected = Vec::new();
+
+        for i in 0..25 {
+            let next = queue.next_offset().unwrap();
+            let offset = queue.append(format!("item {}", i).as_bytes()).unwrap();
+            assert_eq!(offset, next);
+            assert_eq!(queue.last_offset().unwrap(), Some(offset));
+            expected.push(offset);
+        }
+
+        queue.sync().unwrap();
+
+        let items = read_items(queue.stream(None).unwrap());
+        assert!(items
+            .iter()
+            .map(|item| item.id)
+            .eq(expected.iter().cloned()));
+        assert_eq!(queue.first_offset().unwrap(), Some(start));
+
+        // offsets can be used to resume a stream
+        let last = queue.last_offset().unwrap().unwrap();
+        let mut streamer = queue.stream(Some(last)).unwrap();
+        assert_eq!(streamer.next().unwrap().unwrap().data, b"item 24");
+
+        queue.truncate_before(expected[10]).unwrap();
+        let first = queue.first_offset().unwrap().unwrap();
+        assert!(first > start && first <= expected[10]);
+        assert_eq!(
+            queue.stream(None).unwrap().next().unwrap().unwrap().id,
+            first
+        );
+
+        // if we crash directly after moving to a new section, the last
+        // item is found in the previous one
+        let (current, _) = offset_decode(queue.next_offset().unwrap()).unwrap();
+        drop(queue);
+        let (parent, path) = current.next().unwrap().paths(tmp_dir.path());
+        fs::create_dir_all(parent).unwrap();
+        fs::File::create(path).unwrap();
+
+        let mut queue = open();
+        assert_eq!(queue.first_offset().unwrap(), Some(first));
+        assert_eq!(queue.last_offset().unwrap(), Some(last));
+        assert_eq!(
+            queue.next_offset().unwrap(),
+            offset_encode(&current.next().unwrap(), section::HEADER_SIZE as u32)
+        );
+    }
+
+    #[test]
+    fn test_stream_missing_sections() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        // nothing exists yet, so the streamer waits for the first section
+        let mut empty = small_options()
+            .open_reader(tmp_dir.path())
+            .unwrap()
+            .stream(None)
+            .unwrap();
+        assert!(empty.next().unwrap().is_none());
+
+        let (mut queue, ids) = small_queue(tmp_dir.path(), 2500);
+        assert_eq!(empty.next().unwrap().unwrap().data, b"item 0");
+
+        let behind = queue.stream(Some(ids[0])).unwrap();
+
+        // the earliest remaining section is where new streamers begin
+        let target = offset_encode(&Component::from(0, 0, 1, 5).unwrap(), 0);
+        queue.truncate_before(target).unwrap();
+
+        let remaining: Vec<u64> = ids.iter().cloned().filter(|id| *id >= target).collect();
+        let items = read_items(queue.stream(None).unwrap());
+        assert!(items
+            .iter()
+            .map(|item| item.id)
+            .eq(remaining.iter().cloned()));
+
+        // streamers that haven't opened a deleted section skip past it
+        let items = read_items(behind);
+        assert!(items
+            .iter()
+            .map(|item| item.id)
+            .eq(remaining.iter().cloned()));
+
+        // as do streamers that encounter a gap in the sequence
+        let (_, gap_path) = Component::from(0, 0, 1, 10).unwrap().paths(tmp_dir.path());
+        let gap = offset_encode(&Component::from(0, 0, 1, 10).unwrap(), 0);
+        let after_gap = offset_encode(&Component::from(0, 0, 1, 11).unwrap(), 0);
+        fs::remove_file(gap_path).unwrap();
+
+        let items = read_items(queue.stream(None).unwrap());
+        let expected = remaining
+            .iter()
+            .cloned()
+            .filter(|id| *id < gap || *id >= after_gap);
+        assert!(items.iter().map(|item| item.id).eq(expected));
+    }
+
+    #[test]
+    fn test_retention() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let open =
+            |path: &str, options: &QueueOptions| options.open(tmp_dir.path().join(path)).unwrap();
+
+        let mut queue = open("sections", small_options().retention_max_sections(3));
+        for i in 0..200 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+            assert!(
+                depot_sections(tmp_dir.path().join("sections"))
+                    .unwrap()
+                    .len()
+                    <= 3
+            );
+        }
+
+        let mut queue = open("bytes", small_options().retention_max_bytes(500));
+        for i in 0..200 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+        queue.sync().unwrap();
+        let sections = depot_sections(tmp_dir.path().join("bytes")).unwrap();
+        let total: u64 = sections
+            .iter()
+            .map(|(_, path)| fs::metadata(path).unwrap().len())
+            .sum();
+        assert!(total <= 500);
+        assert!(total > 400);
+
+        // indexes count towards the size of their sections
+        let mut queue = open(
+            "indexed",
+            small_options().retention_max_bytes(500).sequences(true),
+        );
+        for i in 0..200 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+        queue.sync().unwrap();
+        queue.enforce_retention().unwrap();
+        let sections = depot_sections(tmp_dir.path().join("indexed")).unwrap();
+        let total: u64 = sections
+            .iter()
+            .map(|(_, path)| {
+                let index_len = fs::metadata(section::index_path(path)).unwrap().len();
+                fs::metadata(path).unwrap().len() + index_len
+            })
+            .sum();
+        assert!(total <= 500);
+        assert!(total > 400);
+
+        let mut queue = open("age", &small_options());
+        for i in 0..200 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+        queue.sync().unwrap();
+        let sections = depot_sections(tmp_dir.path().join("age")).unwrap().len();
+        drop(queue);
+
+        thread::sleep(time::Duration::from_millis(200));
+
+        let mut queue = open(
+            "age",
+            small_options().retention_max_age(time::Duration::from_millis(100)),
+        );
+        assert_eq!(queue.enforce_retention().unwrap(), sections - 1);
+        assert_eq!(queue.enforce_retention().unwrap(), 0);
+
+        // a section that can't be deleted doesn't fail appends, but is
+        // reported by an explicit call
+        let mut queue = open("failed", small_options().retention_max_sections(2));
+        queue.append(b"item").unwrap();
+        let (_, first_path) = Component::new().paths(tmp_dir.path().join("failed"));
+        let index_path = section::index_path(&first_path);
+        fs::create_dir(&index_path).unwrap();
+        for i in 0..10 {
+            queue.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+        assert!(first_path.exists());
+        assert!(queue.enforce_retention().is_err());
+        fs::remove_dir(&index_path).unwrap();
+        assert!(queue.enforce_retention().unwrap() > 0);
+        assert!(!first_path.exists());
+
+        assert!(QueueOptions::new()
+            .retention_max_sections(0)
+            .open(tmp_dir.path())
+            .is_err());
+    }
+
+    #[test]
+    fn test_flush_and_sync() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let mut queue = Queue::new(tmp_dir.path());
+
+        queue.append(b"hello").unwrap();
+
+        let mut reader = queue.stream(None).unwrap();
+        assert!(reader.next().unwrap().is_none());
+
+        queue.flush().unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"hello");
+
+        queue.append(b"world").unwrap();
+        queue.sync().unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"world");
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let visible = |queue: &Queue| queue.stream(None).unwrap().iter().count();
+
+        let mut queue = QueueOptions::new()
+            .sync_policy(SyncPolicy::EveryAppend)
+            .open(tmp_dir.path().join("every-append"))
+            .unwrap();
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 1);
+
+        let mut queue = QueueOptions::new()
+            .sync_policy(SyncPolicy::EveryNItems(3))
+            .open(tmp_dir.path().join("every-n-items"))
+            .unwrap();
+        queue.append(b"hello").unwrap();
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 0);
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 3);
+
+        let mut queue = QueueOptions::new()
+            .sync_policy(SyncPolicy::EveryNBytes(8))
+            .open(tmp_dir.path().join("every-n-bytes"))
+            .unwrap();
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 0);
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 2);
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 2);
+
+        let mut queue = QueueOptions::new()
+            .sync_policy(SyncPolicy::Interval(Duration::from_millis(50)))
+            .open(tmp_dir.path().join("interval"))
+            .unwrap();
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 0);
+        thread::sleep(Duration::from_millis(50));
+        queue.append(b"hello").unwrap();
+        assert_eq!(visible(&queue), 2);
+
+        let path = tmp_dir.path().join("invalid");
+        assert!(QueueOptions::new()
+            .sync_policy(SyncPolicy::EveryNItems(0))
+            .open(&path)
+            .is_err());
+        assert!(QueueOptions::new()
+            .sync_policy(SyncPolicy::EveryNBytes(0))
+            .open(&path)
+            .is_err());
+        assert!(QueueOptions::new()
+            .sync_policy(SyncPolicy::Interval(Duration::from_secs(0)))
+            .open(&path)
+            .is_err());
+    }
+
+    #[test]
+    fn test_checksums() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        {
+            let mut queue = QueueOptions::new()
+                .checksums(true)
+                .open(tmp_dir.path())
+                .unwrap();
+            queue.append(b"hello").unwrap();
+            queue.append(b"world").unwrap();
+            queue.append(b"again").unwrap();
+            queue.sync().unwrap();
+        }
+
+        let (_, path) = Component::new().paths(tmp_dir.path());
+        let mut contents = fs::read(&path).unwrap();
+        let position = contents.windows(5).position(|w| w == b"world").unwrap();
+        contents[position] = b'W';
+        fs::write(&path, &contents).unwrap();
+
+        let queue = Queue::new(tmp_dir.path());
+
+        let mut reader = queue.stream(None).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"hello");
+        assert!(reader.next().is_err());
+        assert_eq!(reader.next().unwrap().unwrap().data, b"again");
+        assert!(reader.next().unwrap().is_none());
+
+        let mut reader = queue.stream(None).unwrap();
+        reader.next_all().unwrap();
+        let (item, item_type) = reader.next_all().unwrap().unwrap();
+        assert_eq!(item.data, b"World");
+        assert_eq!(item_type, QueueItemType::Corrupt);
+    }
+
+    #[test]
+    fn test_errors() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let mut queue = QueueOptions::new()
+            .max_item_size(16)
+            .open(tmp_dir.path())
+            .unwrap();
+
+        match queue.append(&[0; 17]) {
+            Err(DepotError::ItemTooLarge {
+                size: 17,
+                max_item_size: 16,
+            }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match queue.stream(Some(u64::MAX)) {
+            Err(DepotError::InvalidOffset(u64::MAX)) => (),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        queue.append(b"hello").unwrap();
+        queue.append(b"world").unwrap();
+        queue.append(b"again").unwrap();
+        queue.sync().unwrap();
+
+        let ids: Vec<u64> = queue
+            .stream(None)
+            .unwrap()
+            .iter()
+            .map(|item| item.unwrap().id)
+            .collect();
+
+        // an invalid escape sequence can't be skipped over, so the
+        // error is reported rather than treated as the end
+        let (_, path) = Component::new().paths(tmp_dir.path());
+        let mut contents = fs::read(&path).unwrap();
+        let position = contents.windows(5).position(|w| w == b"world").unwrap();
+        contents[position + 2] = b'\\';
+        contents[position + 3] = b'x';
+        fs::write(&path, &contents).unwrap();
+
+        let mut reader = queue.stream(None).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"hello");
+
+        for _ in 0..2 {
+            match reader.next() {
+                Err(DepotError::Corrupt { offset, .. }) => assert_eq!(offset, ids[1]),
+                other => panic!("unexpected result: {:?}", other.map(|i| i.map(|i| i.id))),
+            }
+        }
+
+        let e = io::Error::from(reader.next().unwrap_err());
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_append_batch() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let mut queue = QueueOptions::new()
+            .checksums(true)
+            .max_file_size(128)
+            .max_item_size(16)
+            .write_chunk_size(16)
+            .open(tmp_dir.path())
+            .unwrap();
+
+        assert!(queue.append_batch(&[]).unwrap().is_empty());
+
+        queue.append(b"first").unwrap();
+
+        // items include bytes that must be escaped
+        let items: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("item\n{}\\-", i).into_bytes())
+            .collect();
+        let batch: Vec<&[u8]> = items.iter().map(|item| item.as_slice()).collect();
+
+        let offsets = queue.append_batch(&batch).unwrap();
+        assert_eq!(offsets.len(), 100);
+        assert_eq!(queue.last_offset().unwrap(), offsets.last().cloned());
+        assert!(depot_sections(tmp_dir.path()).unwrap().len() > 1);
+
+        // nothing is appended if any item is too large
+        match queue.append_batch(&[b"ok", &[0; 17]]) {
+            Err(DepotError::ItemTooLarge { size: 17, .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        queue.append(b"last").unwrap();
+        queue.flush().unwrap();
+
+        let read: Vec<(QueueItemType, OwnedQueueItem)> = {
+            let mut streamer = queue.stream(None).unwrap();
+            let mut read = Vec::new();
+
+            while let Some((item, item_type)) = streamer.next_all().unwrap() {
+                read.push((
+                    item_type,
+                    OwnedQueueItem {
+                        id: item.id,
+                        data: item.data.to_vec(),
+                    },
+                ));
+            }
+
+            read
+        };
+
+        assert_eq!(read.len(), 102);
+        assert_eq!(read[0].1.data, b"first");
+        assert_eq!(read[101].1.data, b"last");
+
+        for (i, (item_type, item)) in read[1..101].iter().enumerate() {
+            assert_eq!(*item_type, QueueItemType::Regular);
+            assert_eq!(item.id, offsets[i]);
+            assert_eq!(item.data, items[i]);
+        }
+    }
+
+    #[test]
+    fn test_mmap() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let mut options = QueueOptions::new();
+        options.checksums(true).max_file_size(128).max_item_size(16);
+
+        let mut queue = options.open(tmp_dir.path()).unwrap();
+
+        for i in 0..100 {
+            queue.append(format!("item-{}\n", i).as_bytes()).unwrap();
+        }
+        queue.flush().unwrap();
+
+        let expected = read_items(queue.stream(None).unwrap());
+        assert_eq!(expected.len(), 100);
+        assert!(depot_sections(tmp_dir.path()).unwrap().len() > 2);
+
+        let mut streamer = options
+            .mmap(true)
+            .open_reader(tmp_dir.path())
+            .unwrap()
+            .stream(None)
+            .unwrap();
+
+        for item in &expected {
+            let next = streamer.next().unwrap().unwrap();
+            assert_eq!(next.id, item.id);
+            assert_eq!(next.data, &item.data[..]);
+        }
+
+        assert!(streamer.next().unwrap().is_none());
+
+        // the latest section is still read via a buffer, as it grows
+        queue.append(b"item-100").unwrap();
+        queue.flush().unwrap();
+        assert_eq!(streamer.next().unwrap().unwrap().data, b"item-100");
+
+        // resuming in a sealed section works too
+        let mut streamer = options
+            .open(tmp_dir.path())
+            .unwrap()
+            .stream(Some(expected[50].id))
+            .unwrap();
+        assert_eq!(
+            streamer.next().unwrap().unwrap().data,
+            &expected[50].data[..]
+        );
+    }
+
+    #[test]
+    fn test_get() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let mut queue = QueueOptions::new()
+            .checksums(true)
+            .max_file_size(128)
+            .max_item_size(16)
+            .open(tmp_dir.path())
+            .unwrap();
+
+        let items: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("item-{}\n\\", i).into_bytes())
+            .collect();
+        let offsets: Vec<u64> = items
+            .iter()
+            .map(|item| queue.append(item).unwrap())
+            .collect();
+
+        // items that haven't been flushed are still found
+        for (offset, item) in offsets.iter().zip(items.iter()) {
+            let found = queue.get(*offset).unwrap().unwrap();
+            assert_eq!(found.id, *offset);
+            assert_eq!(found.data, *item);
+        }
+
+        let next = queue.next_offset().unwrap();
+        assert!(queue.get(next).unwrap().is_none());
+
+        for &offset in &[offsets[3] + 1, offsets[3] - 1, offsets[0] - 1] {
+            match queue.get(offset) {
+                Err(DepotError::InvalidOffset(o)) => assert_eq!(o, offset),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        match queue.get(u64::MAX) {
+            Err(DepotError::InvalidOffset(u64::MAX)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let reader = QueueReader::open(tmp_dir.path()).unwrap();
+        assert_eq!(reader.get(offsets[49]).unwrap().unwrap().data, items[49]);
+
+        queue.truncate_before(offsets[25]).unwrap();
+        assert!(queue.get(offsets[0]).unwrap().is_none());
+        assert_eq!(queue.get(offsets[25]).unwrap().unwrap().data, items[25]);
+
+        // corrupt data is reported, rather than treated as missing
+        let (component, id) = offset_decode(offsets[30]).unwrap();
+        let (_, path) = component.paths(tmp_dir.path());
+        let mut contents = fs::read(&path).unwrap();
+        contents[id as usize] ^= 1;
+        fs::write(&path, &contents).unwrap();
+
+        match queue.get(offsets[30]) {
+            Err(DepotError::Corrupt { offset, .. }) => assert_eq!(offset, offsets[30]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_resume_policy() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let mut options = QueueOptions::new();
+        options.max_file_size(128).max_item_size(16);
+
+        let mut queue = options.open(tmp_dir.path()).unwrap();
+        let ids: Vec<u64> = (0..20)
+            .map(|i| queue.append(format!("item\\{}", i).as_bytes()).unwrap())
+            .collect();
+        queue.flush().unwrap();
+
+        let stream = |options: &QueueOptions, id: u64| {
+            let mut streamer = options
+                .open_reader(tmp_dir.path())
+                .unwrap()
+                .stream(Some(id))
+                .unwrap();
+            streamer
+                .next()
+                .map(|item| item.map(|item| item.data.to_vec()))
+        };
+
+        let strict = options.clone();
+        let mut lenient = options.clone();
+        lenient.resume_policy(ResumePolicy::Lenient);
+        let mut lenient_mmap = lenient.clone();
+        lenient_mmap.mmap(true);
+
+        let start = offset_encode(&Component::new(), 0);
+        let header = offset_encode(&Component::new(), 5);
+
+        assert_eq!(stream(&strict, ids[3]).unwrap().unwrap(), b"item\\3");
+        assert_eq!(stream(&strict, start).unwrap().unwrap(), b"item\\0");
+
+        for &offset in &[ids[3] + 2, ids[3] + 5, header] {
+            match stream(&strict, offset) {
+                Err(DepotError::InvalidOffset(o)) => assert_eq!(o, offset),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        for options in &[&lenient, &lenient_mmap] {
+            assert_eq!(stream(options, ids[3] + 2).unwrap().unwrap(), b"item\\4");
+            assert_eq!(stream(options, ids[3] + 5).unwrap().unwrap(), b"item\\4");
+            assert_eq!(stream(options, header).unwrap().unwrap(), b"item\\0");
+        }
+
+        // the error persists, rather than yielding items after it
+        let mut streamer = queue.stream(Some(ids[3] + 1)).unwrap();
+        for _ in 0..2 {
+            assert!(streamer.next().is_err());
+        }
+
+        // an id beyond what has been written is checked once it is
+        let next = queue.next_offset().unwrap();
+        let mut valid = queue.stream(Some(next)).unwrap();
+        let mut invalid = queue.stream(Some(next + 2)).unwrap();
+        assert!(valid.next().unwrap().is_none());
+        assert!(invalid.next().unwrap().is_none());
+
+        queue.append(b"item\\20").unwrap();
+        queue.flush().unwrap();
+
+        assert_eq!(valid.next().unwrap().unwrap().data, b"item\\20");
+        assert!(invalid.next().is_err());
+    }
+
+    #[test]
+    fn test_reader() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("queue");
+
+        match QueueReader::open(&path) {
+            Err(DepotError::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+        assert!(!path.exists());
+
+        // an empty directory is an empty queue, and is left as it is
+        fs::create_dir(&path).unwrap();
+        let reader = QueueReader::open(&path).unwrap();
+        assert!(reader.is_empty().unwrap());
+        assert_eq!(reader.first_offset().unwrap(), None);
+        assert_eq!(reader.last_offset().unwrap(), None);
+        assert!(reader.stream(None).unwrap().next().unwrap().is_none());
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+
+        let (mut queue, ids) = small_queue(&path, 20);
+
+        let reader = small_options().open_reader(&path).unwrap();
+        assert!(!reader.is_empty().unwrap());
+        assert_eq!(
+            reader.first_offset().unwrap(),
+            queue.first_offset().unwrap()
+        );
+        assert_eq!(reader.last_offset().unwrap(), queue.last_offset().unwrap());
+
+        let items = read_items(reader.stream(None).unwrap());
+        assert!(items.iter().map(|item| item.id).eq(ids.iter().cloned()));
+
+        queue.truncate_before(ids[10]).unwrap();
+        assert_eq!(
+            reader.first_offset().unwrap(),
+            queue.first_offset().unwrap()
+        );
+
+        let file_path = path.join("lock");
+        assert!(QueueReader::open(&file_path).is_err());
+    }
+
+    #[test]
+    fn test_reader_partial_item() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let (mut queue, _) = small_queue(tmp_dir.path(), 5);
+
+        let first = queue.first_offset().unwrap();
+        let last = queue.last_offset().unwrap();
+        drop(queue);
+
+        // the writer is part way through the first item of a new section
+        let sections = depot_sections(tmp_dir.path()).unwrap();
+        let (ref component, ref path) = sections[sections.len() - 1];
+        let (parent, next_path) = component.next().unwrap().paths(tmp_dir.path());
+        let mut data = fs::read(path).unwrap();
+        data.truncate(section::HEADER_SIZE);
+        data.extend_from_slice(b"item 5");
+        fs::create_dir_all(parent).unwrap();
+        fs::write(next_path, data).unwrap();
+
+        let reader = QueueReader::open(tmp_dir.path()).unwrap();
+        assert_eq!(reader.first_offset().unwrap(), first);
+        assert_eq!(reader.last_offset().unwrap(), last);
+    }
+
+    #[test]
+    fn test_split() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<QueueWriter>();
+        assert_send_sync::<QueueReaderHandle>();
+
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let (mut writer, reader) = small_options().open(tmp_dir.path()).unwrap().split();
+
+        let handles: Vec<thread::JoinHandle<Vec<Vec<u8>>>> = (0..2)
+            .map(|_| {
+                let reader = reader.clone();
+
+                thread::spawn(move || {
+                    let mut streamer = reader.stream(None).unwrap();
+                    let mut items = Vec::new();
+
+                    while items.len() < 50 {
+                        let timeout = time::Duration::from_secs(10);
+
+                        match streamer.next_blocking(timeout).unwrap() {
+                            Some(item) => items.push(item.data.to_vec()),
+                            None => panic!("timed out after {} items", items.len()),
+                        }
+                    }
+
+                    items
+                })
+            })
+            .collect();
+
+        let flushes = reader.flushes();
+
+        for i in 0..50 {
+            writer.append(format!("item {}", i).as_bytes()).unwrap();
+            writer.flush().unwrap();
+        }
+
+        assert_eq!(
+            reader.wait_for_flush(flushes, time::Duration::from_secs(10)),
+            flushes + 50
+        );
+
+        for handle in handles {
+            let items = handle.join().unwrap();
+
+            for (i, item) in items.iter().enumerate() {
+                assert_eq!(item, format!("item {}", i).as_bytes());
+            }
+        }
+
+        assert_eq!(
+            reader.first_offset().unwrap(),
+            writer.first_offset().unwrap()
         );
+        assert_eq!(reader.last_offset().unwrap(), writer.last_offset().unwrap());
+
+        // times out if nothing is flushed
+        let mut streamer = reader.stream(writer.last_offset().unwrap()).unwrap();
+        assert!(streamer.next().unwrap().is_some());
+        let start = Instant::now();
+        assert!(streamer
+            .next_blocking(time::Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_split_without_flush() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+
+        let (mut writer, reader) = QueueOptions::new()
+            .write_chunk_size(64)
+            .open(tmp_dir.path())
+            .unwrap()
+            .split();
+
+        let handle = {
+            let reader = reader.clone();
+
+            thread::spawn(move || {
+                let mut streamer = reader.stream(None).unwrap();
+                let start = Instant::now();
+                let item = streamer
+                    .next_blocking(time::Duration::from_secs(30))
+                    .unwrap()
+                    .map(|item| item.data.to_vec());
+
+                (item, start.elapsed())
+            })
+        };
+
+        let flushes = reader.flushes();
+
+        // the items overflow the write buffer, so the first of them
+        // reach the file without an explicit flush
+        for i in 0..10 {
+            writer.append(format!("item {}", i).as_bytes()).unwrap();
+        }
+
+        assert!(reader.wait_for_flush(flushes, time::Duration::from_secs(10)) > flushes);
+
+        let (item, elapsed) = handle.join().unwrap();
+        assert_eq!(item.unwrap(), b"item 0");
+        assert!(elapsed < time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_writer_lock() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let options = QueueOptions::new();
+
+        let mut writer = options.try_open_writer(tmp_dir.path()).unwrap();
+        writer.append(b"hello").unwrap();
+        writer.flush().unwrap();
+
+        match options.try_open_writer(tmp_dir.path()) {
+            Err(DepotError::Locked { pid }) => assert_eq!(pid, Some(process::id())),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        // the lock is taken lazily, but a second writer still fails
+        let mut other = Queue::new(tmp_dir.path());
+        match other.append(b"world") {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                format!("queue locked by pid {}", process::id())
+            ),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        // readers don't need the lock
+        let mut reader = other.stream(None).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"hello");
+
+        let handle = {
+            let path = tmp_dir.path().to_owned();
+            thread::spawn(move || QueueOptions::new().open_writer(path).unwrap())
+        };
+
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(!handle.is_finished());
+
+        drop(writer);
+
+        let mut writer = handle.join().unwrap();
+        writer.append(b"world").unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(reader.next().unwrap().unwrap().data, b"world");
+    }
+
+    #[test]
+    fn test_large_items() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let sizes = [65536, 4194304, 1, 1048576, 4194304, 0, 2097152];
+
+        {
+            let mut queue = QueueOptions::new()
+                .max_file_size(8388608)
+                .max_item_size(4194304)
+                .open(tmp_dir.path())
+                .unwrap();
+
+            for (i, &size) in sizes.iter().enumerate() {
+                queue.append(&vec![i as u8; size]).unwrap();
+            }
+
+            assert!(queue.append(&vec![0; 4194305]).is_err());
+            queue.sync().unwrap();
+        }
+
+        let queue = QueueOptions::new()
+            .max_file_size(8388608)
+            .max_item_size(4194304)
+            .open(tmp_dir.path())
+            .unwrap();
+        let items = read_items(queue.stream(None).unwrap());
+
+        assert_eq!(items.len(), sizes.len());
+
+        for (i, item) in items.iter().enumerate() {
+            assert_eq!(item.data, vec![i as u8; sizes[i]]);
+        }
+    }
+
+    #[test]
+    fn test_next_blocking() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let tmp_path = tmp_dir.path().join("queue");
+        let size = 200;
+
+        let open = |path: &Path| small_options().open(path).unwrap();
+
+        // the queue doesn't exist until the writer first appends
+        let mut reader = open(&tmp_path).stream(None).unwrap();
+
+        let producer = {
+            let tmp_path = tmp_path.clone();
+
+            thread::spawn(move || {
+                let mut queue = open(&tmp_path);
+
+                for i in 0..size {
+                    if i % 50 == 0 {
+                        thread::sleep(time::Duration::from_millis(50));
+                    }
+
+                    queue.append(format!("item {}", i).as_bytes()).unwrap();
+                    queue.flush().unwrap();
+                }
+            })
+        };
+
+        for i in 0..size {
+            let item = reader
+                .next_blocking(time::Duration::from_secs(10))
+                .unwrap()
+                .unwrap();
+            assert_eq!(item.data, format!("item {}", i).as_bytes());
+        }
+
+        producer.join().unwrap();
+
+        let start = time::Instant::now();
+        let timeout = time::Duration::from_millis(50);
+        assert!(reader.next_blocking(timeout).unwrap().is_none());
+        assert!(start.elapsed() >= timeout);
     }
 
     #[test]
@@ -695,14 +3409,17 @@ mod tests {
             let tmp_path = tmp_dir.path().to_owned();
 
             thread::spawn(move || {
-                let mut queue =
-                    Queue::_config(&PathBuf::from(&tmp_path), 8388608, 65536, 8192, 8192);
+                let mut queue = QueueOptions::new()
+                    .max_file_size(8388608)
+                    .max_item_size(65536)
+                    .open(&tmp_path)
+                    .unwrap();
 
                 for i in 0..size {
                     let message =
                         format!("the quick brown fox jumped over the lazy dog, -\n #{}", i);
                     let data = message.as_bytes();
-                    queue.append(&data).unwrap();
+                    queue.append(data).unwrap();
                 }
 
                 queue.sync().unwrap();
@@ -713,12 +3430,16 @@ mod tests {
             let tmp_path = tmp_dir.path().to_owned();
 
             thread::spawn(move || {
-                let queue = Queue::_config(&PathBuf::from(&tmp_path), 8388608, 65536, 8192, 8192);
+                let queue = QueueOptions::new()
+                    .max_file_size(8388608)
+                    .max_item_size(65536)
+                    .open(&tmp_path)
+                    .unwrap();
                 let mut reader = queue.stream(None).unwrap();
 
                 for _ in 0..size {
                     loop {
-                        if let Some(_) = reader.next().unwrap() {
+                        if reader.next().unwrap().is_some() {
                             break;
                         } else {
                             thread::sleep(time::Duration::from_millis(10));
@@ -743,12 +3464,16 @@ mod tests {
         {
             let tmp_path = tmp_dir.path().to_owned();
 
-            let mut queue = Queue::_config(&PathBuf::from(&tmp_path), 8388608, 65536, 8192, 8192);
+            let mut queue = QueueOptions::new()
+                .max_file_size(8388608)
+                .max_item_size(65536)
+                .open(&tmp_path)
+                .unwrap();
 
             for i in 0..size {
                 let message = format!("the quick brown fox jumped over the lazy dog, -\n #{}", i);
                 let data = message.as_bytes();
-                queue.append(&data).unwrap();
+                queue.append(data).unwrap();
             }
 
             queue.sync().unwrap();
@@ -757,12 +3482,16 @@ mod tests {
         {
             let tmp_path = tmp_dir.path().to_owned();
 
-            let queue = Queue::_config(&PathBuf::from(&tmp_path), 8388608, 65536, 8192, 8192);
+            let queue = QueueOptions::new()
+                .max_file_size(8388608)
+                .max_item_size(65536)
+                .open(&tmp_path)
+                .unwrap();
             let mut reader = queue.stream(None).unwrap();
 
             for _ in 0..size {
                 loop {
-                    if let Some(_) = reader.next().unwrap() {
+                    if reader.next().unwrap().is_some() {
                         break;
                     } else {
                         thread::sleep(time::Duration::from_millis(10));
@@ -1,30 +1,39 @@
+use crc32c;
+use error::{DepotError, Result};
+use memchr::{memchr, memchr3};
+use memmap2::Mmap;
 use std::cmp;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufWriter, SeekFrom};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::result;
 
 /// A unit of data that is stored in a
 /// section. A `SectionItem` has an id
 /// that can be used to resume from that
 /// position in the section. Items may
 /// be truncated e.g. if there was power
-/// loss.
+/// loss, or corrupt if their checksum
+/// doesn't match their data.
 #[derive(Debug)]
 pub(crate) struct SectionItem<'a> {
     pub id: u32,
     pub data: &'a [u8],
     pub known_eof: bool,
     pub truncated: bool,
-    start: usize,
-    end: usize,
+    pub corrupt: bool,
+    pub sequence: Option<u64>,
 }
 
 struct SectionItemMeta {
     id: u32,
     known_eof: bool,
     truncated: bool,
+    corrupt: bool,
+    mapped: bool,
+    sequence: Option<u64>,
     start: usize,
     end: usize,
 }
@@ -35,7 +44,7 @@ impl<'a> SectionItem<'a> {}
 /// Exceeding this value results in failure, but
 /// this "should" never happen unless there's
 /// external interference. This is max file size
-/// plus three times the maximum item size,
+/// plus the maximum encoded size of an item,
 /// which remains well under the last extra bit,
 /// and provides enough overhead to deal with
 /// encoding overheads. The main idea here is
@@ -47,110 +56,526 @@ const FAIL_FILE_SIZE: u32 = 2_147_483_647;
 
 /// Represents the special values that are escaped
 /// and remapped as part of the on-disk format.
-const MARKER_ESCAPE: u8 = '\\' as u8;
-const MARKER_SEPARATOR: u8 = '\n' as u8;
-const MARKER_SEPARATOR_REMAP: u8 = '$' as u8;
-const MARKER_FAIL: u8 = '-' as u8;
-const MARKER_FAIL_REMAP: u8 = '.' as u8;
+const MARKER_ESCAPE: u8 = b'\\';
+const MARKER_SEPARATOR: u8 = b'\n';
+const MARKER_SEPARATOR_REMAP: u8 = b'$';
+const MARKER_FAIL: u8 = b'-';
+const MARKER_FAIL_REMAP: u8 = b'.';
+
+/// When checksums are enabled, each item's payload is followed
+/// by a CRC32C of the (unencoded) payload, stored as little
+/// endian and encoded with the same escape mechanism.
+const CHECKSUM_SIZE: usize = 4;
+
+/// An absolute max size for items. Larger items
+/// reduce the maximum size of the files that can be
+/// used, given the constraint on `FAIL_FILE_SIZE`.
+pub(crate) const MAX_ITEM_SIZE: u32 = 268_435_456;
+
+/// The most bytes that an item can occupy on disk,
+/// i.e. every byte of it and its checksum escaped,
+/// followed by the markers written during recovery
+/// and the separator.
+fn max_encoded_size(max_item_size: u32) -> u32 {
+    2 * (max_item_size + CHECKSUM_SIZE as u32) + 4
+}
 
 /// If a file is this size or larger, the section
 /// be considered full and no more writes will be
 /// allowed. Note that this means that the size of
-/// a file may exceed this by the maximum item size.
-const MAX_FILE_SIZE: u32 = FAIL_FILE_SIZE - (3 * MAX_ITEM_SIZE);
+/// a file may exceed this by the maximum encoded
+/// item size.
+pub(crate) fn max_file_size(max_item_size: u32) -> u32 {
+    FAIL_FILE_SIZE - max_encoded_size(max_item_size)
+}
 
-const MAX_ITEM_SIZE: u32 = 8192;
+/// The number of bytes initially read when reading a single item.
+/// Most items are small, so more are only read if needed.
+const GET_CHUNK_SIZE: usize = 512;
 
-#[derive(Debug)]
-pub(crate) struct SectionReader {
-    path_buf: PathBuf,
+/// Identifies a file as a Depot section. In the legacy (v0)
+/// format, an escape can only be followed by one of the
+/// remapped values, so a file that starts with these bytes
+/// can always be distinguished from one without a header.
+const HEADER_MAGIC: [u8; 4] = [MARKER_ESCAPE, b'D', b'P', b'T'];
+
+/// The header occupies a fixed number of bytes at the start
+/// of each section file, with unused space reserved for
+/// future versions. Items begin directly after it.
+pub(crate) const HEADER_SIZE: usize = 32;
+
+/// The smallest maximum file size that a section can be created
+/// with, which leaves room after the header for an item of one byte
+/// and its separator.
+pub(crate) const MIN_FILE_SIZE: u32 = HEADER_SIZE as u32 + 2;
+
+const HEADER_VERSION: u16 = 1;
+
+const HEADER_FLAG_CHECKSUMS: u16 = 1;
+
+const HEADER_FLAG_SEQUENCES: u16 = 2;
+
+/// Sections with sequences have an index alongside them, named after
+/// the section with this extension. It holds the id of each complete
+/// item in order, as u32 little endian, so the n-th entry is the id of
+/// the item whose sequence is the section's first sequence plus n.
+const INDEX_EXTENSION: &str = "idx";
+
+const INDEX_ENTRY_SIZE: u64 = 4;
+
+/// Describes the format of a section and the parameters that
+/// it was created with. Readers and writers use these instead
+/// of their own configuration, given that a section may have
+/// been created by a process with different settings.
+///
+/// The on-disk layout (little endian) is:
+///
+/// | bytes  | field          |
+/// |--------|----------------|
+/// | 0..4   | magic          |
+/// | 4..6   | version        |
+/// | 6..8   | flags          |
+/// | 8..12  | max file size  |
+/// | 12..16 | max item size  |
+/// | 16..24 | first sequence |
+/// | 24..32 | reserved       |
+///
+/// The first sequence is only set if the sequences flag is, and is
+/// otherwise reserved.
+#[derive(Debug, PartialEq)]
+struct SectionHeader {
+    checksums: bool,
+    first_sequence: Option<u64>,
     max_file_size: u32,
     max_item_size: u32,
-    read_chunk_size: u32,
 }
 
+/// The result of inspecting the start of a section file.
+#[derive(Debug, PartialEq)]
+enum HeaderStatus {
+    /// The file starts with a complete and valid header.
+    Complete(SectionHeader),
+
+    /// The file is empty or only contains part of a header,
+    /// e.g. because it's in the process of being created.
+    Incomplete,
+
+    /// The file doesn't have a header, i.e. it's a legacy (v0)
+    /// section and items begin at the start of the file.
+    Legacy,
+}
+
+impl SectionHeader {
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut flags = 0;
+        let mut bytes = [0; HEADER_SIZE];
+
+        if self.checksums {
+            flags |= HEADER_FLAG_CHECKSUMS;
+        }
+
+        if let Some(first_sequence) = self.first_sequence {
+            flags |= HEADER_FLAG_SEQUENCES;
+            bytes[16..24].copy_from_slice(&first_sequence.to_le_bytes());
+        }
+
+        bytes[0..4].copy_from_slice(&HEADER_MAGIC);
+        bytes[4..6].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&flags.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.max_file_size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.max_item_size.to_le_bytes());
+        bytes
+    }
+
+    fn inspect(bytes: &[u8]) -> Result<HeaderStatus> {
+        if bytes.len() < HEADER_MAGIC.len() && HEADER_MAGIC.starts_with(bytes) {
+            return Ok(HeaderStatus::Incomplete);
+        } else if !bytes.starts_with(&HEADER_MAGIC) {
+            return Ok(HeaderStatus::Legacy);
+        } else if bytes.len() < HEADER_SIZE {
+            return Ok(HeaderStatus::Incomplete);
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let version = u16_at(4);
+        let flags = u16_at(6);
+        let max_file_size = u32_at(8);
+        let max_item_size = u32_at(12);
+
+        let mut first_sequence = [0; 8];
+        first_sequence.copy_from_slice(&bytes[16..24]);
+
+        if version != HEADER_VERSION {
+            Err(DepotError::corrupt(
+                0,
+                format!("unsupported section version {}", version),
+            ))
+        } else if flags & !(HEADER_FLAG_CHECKSUMS | HEADER_FLAG_SEQUENCES) != 0 {
+            Err(DepotError::corrupt(
+                0,
+                format!("unsupported section flags {}", flags),
+            ))
+        } else if max_item_size == 0
+            || max_item_size > MAX_ITEM_SIZE
+            || max_file_size == 0
+            || max_file_size > self::max_file_size(max_item_size)
+        {
+            Err(DepotError::corrupt(
+                0,
+                "section header contains invalid sizes",
+            ))
+        } else {
+            Ok(HeaderStatus::Complete(SectionHeader {
+                checksums: flags & HEADER_FLAG_CHECKSUMS != 0,
+                first_sequence: if flags & HEADER_FLAG_SEQUENCES != 0 {
+                    Some(u64::from_le_bytes(first_sequence))
+                } else {
+                    None
+                },
+                max_file_size,
+                max_item_size,
+            }))
+        }
+    }
+
+    /// Reads the start of the file and inspects it. The file's
+    /// position is left unspecified.
+    fn read(file: &mut File) -> Result<HeaderStatus> {
+        let mut bytes = [0; HEADER_SIZE];
+        let mut len = 0;
+
+        file.seek(SeekFrom::Start(0))?;
+
+        while len < HEADER_SIZE {
+            match file.read(&mut bytes[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        SectionHeader::inspect(&bytes[0..len])
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SectionReader;
+
 impl SectionReader {
-    pub(crate) fn new<'a>(
+    #[allow(clippy::new_ret_no_self)]
+    pub(crate) fn new(
         path: PathBuf,
         max_file_size: u32,
         max_item_size: u32,
         read_chunk_size: u32,
         id: Option<u32>,
-    ) -> io::Result<SectionStreamingIterator> {
-        let max_file_size = cmp::min(MAX_FILE_SIZE, max_file_size);
+    ) -> Result<SectionStreamingIterator> {
         let max_item_size = cmp::min(MAX_ITEM_SIZE, max_item_size);
+        let max_file_size = cmp::min(self::max_file_size(max_item_size), max_file_size);
 
-        if max_item_size != MAX_ITEM_SIZE || read_chunk_size != MAX_ITEM_SIZE {
-            // The current implementation requires the buffer size and maximum item
-            // size be 8K. This may change to dynamically allocate a vector for large
-            // items, at the cost of performance, but has not been implemented yet
-            // due to complexity.
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "max_item_size and read_chunk_size are not currently configurable",
+        if read_chunk_size == 0 {
+            return Err(DepotError::InvalidConfig(
+                "read_chunk_size must be greater than zero".to_string(),
             ));
         }
 
-        let mut file = OpenOptions::new().read(true).open(&path)?;
-
-        let position = if let Some(requested_id) = id {
-            file.seek(SeekFrom::Start(requested_id as u64))?;
-            requested_id
-        } else {
-            0
-        };
+        let file = OpenOptions::new().read(true).open(&path)?;
 
-        let iterator = SectionStreamingIterator {
+        let mut iterator = SectionStreamingIterator {
             always_fail: false,
-            file: file,
-            item_buf: [0; MAX_ITEM_SIZE as usize],
+            checksums: false,
+            data_start: 0,
+            discard: false,
+            file,
+            first_sequence: None,
+            header_pending: true,
+            item_buf: vec![0; read_chunk_size as usize],
             item_len: 0,
             item_start: 0,
             max_file_size,
+            max_item_size,
             current: Ok(None),
-            position,
+            lenient: false,
+            map: None,
+            path,
+            position: id.unwrap_or(0),
+            resume: id.filter(|&id| id > 0),
+            scan_start: 0,
+            sequence: None,
+            sequence_pending: false,
         };
 
+        iterator.read_header()?;
+
         Ok(iterator)
     }
+
+    /// Returns the ids of the first and last items of a section, or
+    /// `None` if it has no complete items.
+    pub(crate) fn bounds(path: &Path, read_chunk_size: u32) -> Result<Option<(u32, u32)>> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let length = file.metadata()?.len();
+
+        if length > FAIL_FILE_SIZE as u64 {
+            return Err(DepotError::corrupt(0, "file size exceeds maximum"));
+        }
+
+        let start = match SectionHeader::read(&mut file)? {
+            HeaderStatus::Complete(_) => HEADER_SIZE as u32,
+            HeaderStatus::Incomplete => return Ok(None),
+            HeaderStatus::Legacy => 0,
+        };
+
+        let last = last_id(&mut file, start, length as u32, read_chunk_size)?;
+
+        Ok(last.map(|last| (start, last)))
+    }
+
+    /// Returns the sequence of the first item of a section and the
+    /// number of items that have been indexed, or `None` if it doesn't
+    /// have sequences.
+    pub(crate) fn sequences(path: &Path) -> Result<Option<(u64, u64)>> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let first = match SectionHeader::read(&mut file)? {
+            HeaderStatus::Complete(SectionHeader {
+                first_sequence: Some(first),
+                ..
+            }) => first,
+
+            _ => return Ok(None),
+        };
+
+        match File::open(index_path(path)) {
+            Ok(index) => Ok(Some((first, index_len(&index)?))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Some((first, 0))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the number of items in a section, excluding truncated
+    /// ones, i.e. the number of sequences it would have been given.
+    pub(crate) fn items(path: &Path, read_chunk_size: u32) -> Result<u64> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let length = file.metadata()?.len();
+
+        if length > FAIL_FILE_SIZE as u64 {
+            return Err(DepotError::corrupt(0, "file size exceeds maximum"));
+        }
+
+        let start = match SectionHeader::read(&mut file)? {
+            HeaderStatus::Complete(_) => HEADER_SIZE as u32,
+            HeaderStatus::Incomplete => return Ok(0),
+            HeaderStatus::Legacy => 0,
+        };
+
+        let ids = item_ids(&mut file, start, length as u32, read_chunk_size)?;
+
+        Ok(ids.len() as u64)
+    }
+
+    /// Returns the id of the n-th item of a section with sequences, or
+    /// `None` if it hasn't been indexed, i.e. written, yet.
+    pub(crate) fn nth_id(path: &Path, n: u64) -> Result<Option<u32>> {
+        let mut index = match File::open(index_path(path)) {
+            Ok(index) => index,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if n < index_len(&index)? {
+            Ok(Some(index_entry(&mut index, n)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl SectionReader {
+    /// Reads the item with the provided id, or `None` if it hasn't
+    /// been completely written yet. The id must be that of an item,
+    /// i.e. where the section's data starts or directly after a
+    /// separator, as a separator can't occur within an encoded item.
+    ///
+    /// Unlike streaming, a truncated item is reported as corrupt,
+    /// as is one whose checksum doesn't match.
+    pub(crate) fn get(path: &Path, max_item_size: u32, id: u32) -> Result<Option<Vec<u8>>> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let (checksums, max_item_size, data_start) = match SectionHeader::read(&mut file)? {
+            HeaderStatus::Complete(header) => {
+                (header.checksums, header.max_item_size, HEADER_SIZE as u32)
+            }
+
+            HeaderStatus::Incomplete => return Ok(None),
+
+            HeaderStatus::Legacy => (false, cmp::min(MAX_ITEM_SIZE, max_item_size), 0),
+        };
+
+        if id < data_start {
+            return Err(DepotError::InvalidOffset(id as u64));
+        }
+
+        // The byte before the item is read too, to check that it's a
+        // separator, unless the item is the first.
+        let skip = if id > data_start { 1 } else { 0 };
+        let limit = max_encoded_size(max_item_size) as usize;
+
+        let mut buf = vec![0; GET_CHUNK_SIZE];
+        let mut len = 0;
+        let mut end = None;
+
+        file.seek(SeekFrom::Start((id - skip as u32) as u64))?;
+
+        while end.is_none() {
+            if len == buf.len() {
+                buf.resize(len * 2, 0);
+            }
+
+            let read = file.read(&mut buf[len..])?;
+
+            if read == 0 {
+                break;
+            }
+
+            let searched = cmp::max(len, skip);
+            len += read;
+
+            if skip == 1 && buf[0] != MARKER_SEPARATOR {
+                return Err(DepotError::InvalidOffset(id as u64));
+            }
+
+            end = memchr(MARKER_SEPARATOR, &buf[searched..len]).map(|n| searched + n);
+
+            if end.is_none() && len - skip >= limit {
+                return Err(DepotError::corrupt(id as u64, "item exceeds max item size"));
+            }
+        }
+
+        // The item hasn't been completely written yet, or the id is
+        // beyond the end of the section.
+        let end = match end {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+
+        if end > skip && buf[end - 1] == MARKER_FAIL {
+            return Err(DepotError::corrupt(id as u64, "item is truncated"));
+        }
+
+        buf.truncate(end);
+        buf.drain(0..skip);
+
+        let len = unescape(&mut buf).map_err(|byte| {
+            DepotError::corrupt(id as u64, format!("invalid byte {} after escape", byte))
+        })?;
+
+        buf.truncate(len);
+
+        if checksums {
+            match verify(&buf) {
+                (len, true) => buf.truncate(len),
+                (_, false) => return Err(DepotError::corrupt(id as u64, "checksum mismatch")),
+            }
+        }
+
+        Ok(Some(buf))
+    }
 }
 
 pub(crate) struct SectionStreamingIterator {
     always_fail: bool,
+    checksums: bool,
+    data_start: u32,
+    discard: bool,
     file: File,
-    item_buf: [u8; MAX_ITEM_SIZE as usize],
+    first_sequence: Option<u64>,
+    header_pending: bool,
+    item_buf: Vec<u8>,
     item_start: usize,
     item_len: usize,
     max_file_size: u32,
-    current: io::Result<Option<SectionItemMeta>>,
+    max_item_size: u32,
+    current: Result<Option<SectionItemMeta>>,
+    lenient: bool,
+    map: Option<Mmap>,
+    path: PathBuf,
     position: u32,
+    resume: Option<u32>,
+    scan_start: usize,
+    sequence: Option<u64>,
+    sequence_pending: bool,
 }
 
 impl SectionStreamingIterator {
-    pub(crate) fn current<'a>(&'a self) -> io::Result<Option<SectionItem<'a>>> {
+    pub(crate) fn current(&self) -> Result<Option<SectionItem<'_>>> {
         match self.current {
             Ok(Some(ref s)) => Ok(Some(SectionItem {
                 id: s.id,
-                data: &self.item_buf[s.start..s.end],
+                data: match self.map {
+                    Some(ref map) if s.mapped => &map[s.start..s.end],
+                    _ => &self.item_buf[s.start..s.end],
+                },
                 known_eof: s.known_eof,
                 truncated: s.truncated,
-                start: s.start,
-                end: s.end,
+                corrupt: s.corrupt,
+                sequence: s.sequence,
             })),
 
             Ok(None) => Ok(None),
 
-            Err(ref _e) => Ok(None), // @TODO fixme
+            Err(ref e) => Err(e.duplicate()),
         }
     }
 
     pub(crate) fn advance(&mut self) {
+        // the error that halted reading remains the current one
         if self.always_fail {
-            // @TODO make this a nicer error message
-            self.current = Err(io::Error::new(
-                io::ErrorKind::Other,
-                "a previous error has halted further execution",
-            ));
+            return;
+        }
+
+        if self.header_pending {
+            match self.read_header() {
+                Ok(()) if self.header_pending => {
+                    self.current = Ok(None);
+                    return;
+                }
+
+                Ok(()) => (),
+
+                Err(e) => {
+                    self.always_fail = true;
+                    self.current = Err(e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(id) = self.resume {
+            match self.check_resume(id) {
+                Ok(true) => self.resume = None,
+
+                Ok(false) => {
+                    self.current = Ok(None);
+                    return;
+                }
+
+                Err(e) => {
+                    self.always_fail = true;
+                    self.current = Err(e);
+                    return;
+                }
+            }
+        }
+
+        if self.map.is_some() {
+            self.advance_mapped();
             return;
         }
 
@@ -158,111 +583,338 @@ impl SectionStreamingIterator {
         //                      on each subsequent call, extract the next item
         //                      from memory, returning a reference to its data
         //
-        //                      once no more items can be extracted, shift
-        //                      remaining data over and repeat
+        //                      once no more items can be extracted, move the
+        //                      remaining data to the front if the buffer is
+        //                      running out of room, growing it if an item
+        //                      doesn't fit in it, and repeat
 
         loop {
-            let mut need_decode = false;
-            let mut last_byte = 0;
-
-            for i in self.item_start..self.item_len {
-                let byte = self.item_buf[i];
-
-                if byte == MARKER_SEPARATOR {
-                    let next_position = self.position + ((i - self.item_start) as u32) + 1;
-                    let truncated = last_byte == MARKER_FAIL;
-
-                    let mut escaped = false;
-                    let mut shifted = 0;
-
-                    if need_decode && !truncated {
-                        for j in self.item_start..i {
-                            let byte = self.item_buf[j];
-
-                            if escaped {
-                                escaped = false;
-
-                                if byte == MARKER_FAIL_REMAP {
-                                    self.item_buf[j - shifted] = MARKER_FAIL;
-                                } else if byte == MARKER_SEPARATOR_REMAP {
-                                    self.item_buf[j - shifted] = MARKER_SEPARATOR;
-                                } else if byte == MARKER_ESCAPE {
-                                    self.item_buf[j - shifted] = MARKER_ESCAPE;
-                                } else {
-                                    self.always_fail = true;
-
-                                    self.current = Err(io::Error::new(
-                                        io::ErrorKind::InvalidData,
-                                        format!(
-                                            "cannot parse file, invalid byte {} after escape",
-                                            byte
-                                        ),
-                                    ));
-                                    return;
-                                }
-                            } else if byte == MARKER_ESCAPE {
-                                escaped = true;
-                                shifted += 1;
-                            } else if shifted != 0 {
-                                self.item_buf[j - shifted] = byte;
-                            }
+            if let Some(n) = memchr(
+                MARKER_SEPARATOR,
+                &self.item_buf[self.scan_start..self.item_len],
+            ) {
+                let i = self.scan_start + n;
+                let start = self.item_start;
+                let next_position = self.position + ((i - start) as u32) + 1;
+
+                if self.discard {
+                    self.discard = false;
+                    self.item_start = i + 1;
+                    self.scan_start = i + 1;
+                    self.position = next_position;
+                    continue;
+                }
+                let truncated = i > start && self.item_buf[i - 1] == MARKER_FAIL;
+
+                let mut end = i;
+
+                // truncated items may end partway through an escape, so
+                // they're returned as they are
+                if !truncated {
+                    match unescape(&mut self.item_buf[start..i]) {
+                        Ok(len) => end = start + len,
+
+                        Err(byte) => {
+                            self.always_fail = true;
+
+                            self.current = Err(DepotError::corrupt(
+                                self.position as u64,
+                                format!("invalid byte {} after escape", byte),
+                            ));
+                            return;
                         }
                     }
+                }
 
-                    let item = SectionItemMeta {
-                        id: self.position,
-                        known_eof: next_position > self.max_file_size,
-                        truncated,
-                        start: self.item_start,
-                        end: i,
-                    };
-
-                    self.item_start = i + 1;
-                    self.position = next_position;
+                let mut corrupt = false;
 
-                    self.current = Ok(Some(item));
-                    return;
-                } else if byte == MARKER_FAIL {
-                    need_decode = true;
+                if self.checksums && !truncated {
+                    let (len, valid) = verify(&self.item_buf[start..end]);
+                    corrupt = !valid;
+                    end = start + len;
                 }
 
-                last_byte = byte;
+                let id = self.position;
+
+                let item = SectionItemMeta {
+                    id,
+                    known_eof: next_position >= self.max_file_size,
+                    truncated,
+                    corrupt,
+                    mapped: false,
+                    sequence: self.next_sequence(id, truncated),
+                    start,
+                    end,
+                };
+
+                self.item_start = i + 1;
+                self.scan_start = i + 1;
+                self.position = next_position;
+
+                self.current = Ok(Some(item));
+                return;
             }
 
-            let next_item_len = {
-                let mut k = 0;
-                for j in self.item_start..self.item_len {
-                    self.item_buf[k] = self.item_buf[j];
-                    k += 1;
-                }
-                k
-            };
+            // the buffered bytes don't contain a separator, so they
+            // needn't be searched again once more have been read
+            self.scan_start = self.item_len;
+
+            let pending = self.item_len - self.item_start;
+            let limit = max_encoded_size(self.max_item_size) as usize;
+
+            if pending >= limit {
+                self.current = Err(DepotError::corrupt(
+                    self.position as u64,
+                    "item exceeds max item size",
+                ));
+                return;
+            }
 
-            self.item_start = 0;
-            self.item_len = next_item_len;
+            // Moving the pending bytes is cheap, as they're at most one
+            // item, but it's only done when less than half of the buffer
+            // is free, so that reads remain large.
+            if self.item_start > 0 && self.item_len > self.item_buf.len() / 2 {
+                self.item_buf.copy_within(self.item_start..self.item_len, 0);
+                self.item_start = 0;
+                self.item_len = pending;
+                self.scan_start = pending;
+            }
+
+            if self.item_len == self.item_buf.len() {
+                let len = cmp::min(self.item_len * 2, limit);
+                self.item_buf.resize(len, 0);
+            }
 
             match self.file.read(&mut self.item_buf[self.item_len..]) {
                 Ok(read) => {
                     self.item_len += read;
 
                     if read == 0 {
-                        self.current = if self.item_len > 0 {
-                            Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "maximum item size exceeded",
-                            ))
-                        } else {
-                            Ok(None)
-                        };
+                        // Any remaining bytes are an item that the writer
+                        // hasn't finished writing yet. They're kept in the
+                        // buffer, so the next call resumes at its start
+                        // once more of it has been written.
+                        self.current = Ok(None);
                         return;
                     }
                 }
 
                 Err(e) => {
-                    self.current = Err(e);
+                    self.current = Err(e.into());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl SectionStreamingIterator {
+    /// Sets whether an id to resume from that isn't the start of an
+    /// item is skipped past, rather than reported as invalid.
+    pub(crate) fn lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Memory-maps the section, so that subsequent items are read
+    /// from the mapping rather than via a buffer. This must only be
+    /// done once the section has been sealed, i.e. will no longer be
+    /// written to, and before any items have been read.
+    pub(crate) fn map(&mut self) -> Result<()> {
+        if self.header_pending {
+            return Ok(());
+        }
+
+        // This is safe as long as the file isn't truncated or modified
+        // while it's mapped. Depot doesn't modify sealed sections, and
+        // removing them via retention unlinks rather than truncates.
+        let map = unsafe { Mmap::map(&self.file)? };
+
+        self.map = Some(map);
+        self.item_buf = Vec::new();
+        self.item_len = 0;
+        self.item_start = 0;
+        self.scan_start = 0;
+
+        Ok(())
+    }
+
+    /// Advances to the next item in a mapped section. Items are given
+    /// out directly from the mapping, unless they need to be decoded,
+    /// in which case they're copied to the buffer first.
+    fn advance_mapped(&mut self) {
+        let map = match self.map {
+            Some(ref map) => map,
+            None => return,
+        };
+
+        let start = self.position as usize;
+        let remaining = map.get(start..).unwrap_or(&[]);
+
+        // A sealed section ends with a separator, as the writer only
+        // recovers the latest section after a crash. Any bytes after
+        // the last one are ignored, as the buffered reader does.
+        let i = match memchr(MARKER_SEPARATOR, remaining) {
+            Some(n) => start + n,
+
+            None => {
+                self.current = Ok(None);
+                return;
+            }
+        };
+
+        let next_position = self.position + ((i - start) as u32) + 1;
+
+        if self.discard {
+            self.discard = false;
+            self.position = next_position;
+            return self.advance_mapped();
+        }
+
+        let truncated = i > start && map[i - 1] == MARKER_FAIL;
+        let mapped = truncated || memchr(MARKER_ESCAPE, &map[start..i]).is_none();
+
+        let (data, offset) = if mapped {
+            (&map[start..i], start)
+        } else {
+            self.item_buf.clear();
+            self.item_buf.extend_from_slice(&map[start..i]);
+
+            match unescape(&mut self.item_buf) {
+                Ok(len) => (&self.item_buf[0..len], 0),
+
+                Err(byte) => {
+                    self.always_fail = true;
+
+                    self.current = Err(DepotError::corrupt(
+                        self.position as u64,
+                        format!("invalid byte {} after escape", byte),
+                    ));
                     return;
                 }
             }
+        };
+
+        let (len, corrupt) = if self.checksums && !truncated {
+            let (len, valid) = verify(data);
+            (len, !valid)
+        } else {
+            (data.len(), false)
+        };
+
+        let id = self.position;
+
+        let item = SectionItemMeta {
+            id,
+            known_eof: next_position >= self.max_file_size,
+            truncated,
+            corrupt,
+            mapped,
+            sequence: self.next_sequence(id, truncated),
+            start: offset,
+            end: offset + len,
+        };
+
+        self.position = next_position;
+        self.current = Ok(Some(item));
+    }
+
+    /// Attempts to determine the format of the section from its
+    /// header, positioning the file at the first item to be read.
+    /// If the header has only been partially written, this leaves
+    /// `header_pending` set so that it is retried on the next call.
+    fn read_header(&mut self) -> Result<()> {
+        let data_start = match SectionHeader::read(&mut self.file)? {
+            HeaderStatus::Complete(header) => {
+                self.checksums = header.checksums;
+                self.first_sequence = header.first_sequence;
+                self.max_file_size = header.max_file_size;
+                self.max_item_size = header.max_item_size;
+                HEADER_SIZE as u32
+            }
+
+            HeaderStatus::Incomplete => {
+                return Ok(());
+            }
+
+            HeaderStatus::Legacy => 0,
+        };
+
+        self.data_start = data_start;
+        self.header_pending = false;
+        self.position = cmp::max(self.position, data_start);
+        self.file.seek(SeekFrom::Start(self.position as u64))?;
+
+        // Resuming at the first item, its sequence is known. Otherwise,
+        // it's looked up once the first item has been read.
+        if self.position == data_start {
+            self.sequence = self.first_sequence;
+        } else {
+            self.sequence_pending = self.first_sequence.is_some();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the sequence of an item that has just been read, if the
+    /// section has sequences, and moves on to the next. Truncated items
+    /// don't have one.
+    ///
+    /// The first item after resuming mid-section is looked up in the
+    /// index, and those that follow it are counted from there. If it
+    /// can't be found, its sequence and those that follow are unknown.
+    fn next_sequence(&mut self, id: u32, truncated: bool) -> Option<u64> {
+        if truncated {
+            return None;
+        }
+
+        if self.sequence_pending {
+            self.sequence_pending = false;
+
+            self.sequence = match (self.first_sequence, index_find(&self.path, id)) {
+                (Some(first), Ok(Some(n))) => Some(first + n),
+                _ => None,
+            };
+        }
+
+        let sequence = self.sequence;
+        self.sequence = sequence.map(|s| s + 1);
+        sequence
+    }
+
+    /// Checks that the id the reader was created with is the start
+    /// of an item, as it may have come from a stale or corrupt
+    /// checkpoint. If it isn't, an error is returned, or in lenient
+    /// mode, the reader skips to the start of the next item.
+    ///
+    /// Returns false if the check can't be made yet, because the
+    /// section hasn't been written up to the id.
+    fn check_resume(&mut self, id: u32) -> Result<bool> {
+        // ids within the header resume at the first item
+        if id < self.data_start && self.lenient {
+            return Ok(true);
+        } else if id < self.data_start {
+            return Err(DepotError::InvalidOffset(id as u64));
+        } else if id == self.data_start {
+            return Ok(true);
+        }
+
+        // Nothing has been read yet, so the file is positioned at the
+        // id, and is again once the byte before it has been read.
+        let mut byte = [0; 1];
+        self.file.seek(SeekFrom::Start(id as u64 - 1))?;
+
+        if self.file.read(&mut byte)? == 0 {
+            self.file.seek(SeekFrom::Start(id as u64))?;
+            return Ok(false);
+        }
+
+        if byte[0] == MARKER_SEPARATOR {
+            Ok(true)
+        } else if self.lenient {
+            self.discard = true;
+            Ok(true)
+        } else {
+            Err(DepotError::InvalidOffset(id as u64))
         }
     }
 }
@@ -275,30 +927,51 @@ impl SectionStreamingIterator {
 #[derive(Debug)]
 pub(crate) struct SectionWriter {
     buffer: BufWriter<File>,
-    item_buffer: [u8; 2],
+    checksums: bool,
+    data_start: u32,
+    encode_buffer: Vec<u8>,
+    first_sequence: Option<u64>,
+    index: Option<BufWriter<File>>,
+    index_buffer: Vec<u8>,
+    items: u64,
     last_id: Option<u32>,
     position: u32,
     max_file_size: u32,
     max_item_size: u32,
-    read_chunk_size: u32,
-    write_chunk_size: u32,
 }
 
 impl SectionWriter {
+    /// Returns whether opening a writer for the section at the provided
+    /// path writes its header, i.e. it doesn't exist yet, or a crash
+    /// occurred while writing its header.
+    pub(crate) fn writes_header(path: &Path) -> Result<bool> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e.into()),
+        };
+
+        match SectionHeader::read(&mut file)? {
+            HeaderStatus::Incomplete => Ok(true),
+            HeaderStatus::Complete(_) | HeaderStatus::Legacy => Ok(false),
+        }
+    }
+
     pub(crate) fn new(
         path: &PathBuf,
         max_file_size: u32,
         max_item_size: u32,
         read_chunk_size: u32,
         write_chunk_size: u32,
-    ) -> io::Result<SectionWriter> {
-        let max_file_size = cmp::min(MAX_FILE_SIZE, max_file_size);
+        checksums: bool,
+        first_sequence: Option<u64>,
+    ) -> Result<SectionWriter> {
         let max_item_size = cmp::min(MAX_ITEM_SIZE, max_item_size);
+        let max_file_size = cmp::min(self::max_file_size(max_item_size), max_file_size);
 
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
-            .write(true)
             .read(true)
             .open(path)?;
 
@@ -310,24 +983,53 @@ impl SectionWriter {
             // this should be a very rare condition,
             // but it's possible if another process
             // or user has tampered with data
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "file size exceeds maximum",
-            ));
+            return Err(DepotError::corrupt(0, "file size exceeds maximum"));
         }
 
-        let position = if length > 0 {
-            let mut buf = vec![0u8; 3];
-            file.seek(SeekFrom::Start(length - 1))?;
-            file.read_exact(&mut buf[0..1])?;
+        let (header, data_start, length) = match SectionHeader::read(&mut file)? {
+            HeaderStatus::Complete(header) => (header, HEADER_SIZE as u64, length),
+
+            HeaderStatus::Incomplete => {
+                // The file is new, or we crashed while creating it. Either
+                // way, there are no items so it's safe to (re)write the header.
+                let header = SectionHeader {
+                    checksums,
+                    first_sequence,
+                    max_file_size,
+                    max_item_size,
+                };
 
-            let length = if buf[0] == MARKER_SEPARATOR as u8 {
-                length
-            } else {
-                // We must have crashed before flushing to disk
-                // Determine if we need to append the fail markers
-                // -- two are needed incase we failed directly after
-                // writing an escape character.
+                file.set_len(0)?;
+                file.write_all(&header.encode())?;
+                file.flush()?;
+
+                (header, HEADER_SIZE as u64, HEADER_SIZE as u64)
+            }
+
+            HeaderStatus::Legacy => {
+                let header = SectionHeader {
+                    checksums: false,
+                    first_sequence: None,
+                    max_file_size,
+                    max_item_size,
+                };
+
+                (header, 0, length)
+            }
+        };
+
+        let position = if length > data_start {
+            let mut buf = [0u8; 3];
+            file.seek(SeekFrom::Start(length - 1))?;
+            file.read_exact(&mut buf[0..1])?;
+
+            let length = if buf[0] == MARKER_SEPARATOR {
+                length
+            } else {
+                // We must have crashed before flushing to disk
+                // Determine if we need to append the fail markers
+                // -- two are needed incase we failed directly after
+                // writing an escape character.
                 //
                 // If the last two characters of the file are the
                 // fail marker, then we just need to append the newline.
@@ -340,24 +1042,20 @@ impl SectionWriter {
                 // Note that we don't do anything about the end of record
                 // marker here as we are indeed truncated.
 
-                let write_marker = if length > 2 {
+                let write_marker = if length - data_start > 2 {
                     file.seek(SeekFrom::Start(length - 3))?;
                     file.read_exact(&mut buf[0..3])?;
-                    buf[0] != MARKER_FAIL as u8 || buf[1] != MARKER_FAIL as u8
+                    buf[0] != MARKER_FAIL || buf[1] != MARKER_FAIL
                 } else {
                     true
                 };
 
                 if write_marker {
-                    file.write_all(&[
-                        MARKER_FAIL as u8,
-                        MARKER_FAIL as u8,
-                        MARKER_SEPARATOR as u8,
-                    ])?;
+                    file.write_all(&[MARKER_FAIL, MARKER_FAIL, MARKER_SEPARATOR])?;
                     file.flush()?;
                     length + 3
                 } else {
-                    file.write_all(&[MARKER_SEPARATOR as u8])?;
+                    file.write_all(&[MARKER_SEPARATOR])?;
                     file.flush()?;
                     length + 1
                 }
@@ -367,152 +1065,503 @@ impl SectionWriter {
             // earlier in this fn
             length as u32
         } else {
-            0
+            data_start as u32
         };
 
         file.seek(SeekFrom::Start(position as u64))?;
 
-        let last_id = last_id(&mut file, position as u32, read_chunk_size)?;
+        let last_id = last_id(&mut file, data_start as u32, position, read_chunk_size)?;
+
+        let (index, items) = match header.first_sequence {
+            Some(_) => {
+                let (index, items) = open_index(
+                    path,
+                    &mut file,
+                    data_start as u32,
+                    position,
+                    read_chunk_size,
+                )?;
+
+                (
+                    Some(BufWriter::with_capacity(write_chunk_size as usize, index)),
+                    items,
+                )
+            }
 
-        let buffer = BufWriter::with_capacity(write_chunk_size as usize, file);
+            None => (None, 0),
+        };
 
-        // Upto 2 bytes are needed for each step -- the relevant data and possibly
-        // an escape.
-        let item_buffer: [u8; 2] = [0; 2];
+        let buffer = BufWriter::with_capacity(write_chunk_size as usize, file);
 
         Ok(SectionWriter {
             buffer,
-            item_buffer,
+            checksums: header.checksums,
+            data_start: data_start as u32,
+            encode_buffer: Vec::new(),
+            first_sequence: header.first_sequence,
+            index,
+            index_buffer: Vec::new(),
+            items,
             last_id,
             position,
-            max_file_size,
-            max_item_size,
-            read_chunk_size,
-            write_chunk_size,
+            max_file_size: header.max_file_size,
+            max_item_size: header.max_item_size,
         })
     }
 
-    pub(crate) fn append(&mut self, data: &[u8]) -> io::Result<()> {
-        if data.len() > self.max_item_size as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "item exceeds max item size",
-            ));
+    /// Appends an item to the section, returning its id. The caller
+    /// must check that the section isn't full first.
+    pub(crate) fn append(&mut self, data: &[u8]) -> Result<u32> {
+        self.check_size(data)?;
+
+        if self.is_full() {
+            unreachable!("appended to a full section");
         }
 
+        let id = self.encode(data);
+        self.write_encoded()?;
+
         if self.is_full() {
-            return Err(io::Error::new(io::ErrorKind::Other, "section is full"));
+            self.flush()?;
         }
 
-        let next_id = self.position;
+        Ok(id)
+    }
 
-        for &byte in data {
-            match byte {
-                MARKER_ESCAPE => {
-                    self.item_buffer[0] = MARKER_ESCAPE;
-                    self.item_buffer[1] = MARKER_ESCAPE;
-                    self.buffer.write_all(&self.item_buffer[0..2])?;
-                    self.position += 2;
-                }
+    /// Appends as many of the provided items as fit in the section,
+    /// returning their ids. Fewer ids than items are returned if the
+    /// section became full, and the rest belong in the next section.
+    ///
+    /// Items are encoded together and written in chunks, rather than
+    /// one at a time. If any item is too large, none are appended.
+    pub(crate) fn append_batch(&mut self, items: &[&[u8]]) -> Result<Vec<u32>> {
+        for data in items {
+            self.check_size(data)?;
+        }
 
-                MARKER_SEPARATOR => {
-                    self.item_buffer[0] = MARKER_ESCAPE;
-                    self.item_buffer[1] = MARKER_SEPARATOR_REMAP;
-                    self.buffer.write_all(&self.item_buffer[0..2])?;
-                    self.position += 2;
-                }
+        let mut ids = Vec::with_capacity(items.len());
 
-                MARKER_FAIL => {
-                    self.item_buffer[0] = MARKER_ESCAPE;
-                    self.item_buffer[1] = MARKER_FAIL_REMAP;
-                    self.buffer.write_all(&self.item_buffer[0..2])?;
-                    self.position += 2;
-                }
+        for data in items {
+            if self.is_full() {
+                break;
+            }
 
-                other => {
-                    self.item_buffer[0] = other;
-                    self.buffer.write_all(&self.item_buffer[0..1])?;
-                    self.position += 1;
-                }
-            };
+            ids.push(self.encode(data));
+
+            if self.encode_buffer.len() >= self.buffer.capacity() {
+                self.write_encoded()?;
+            }
         }
 
-        self.buffer.write_all(&[MARKER_SEPARATOR as u8])?;
-        self.position += 1;
-        self.last_id = Some(next_id);
+        self.write_encoded()?;
 
         if self.is_full() {
-            self.sync()?;
+            self.flush()?;
         }
 
-        Ok(())
+        Ok(ids)
     }
 
     pub(crate) fn is_empty(&self) -> bool {
-        self.last_id == None
+        self.last_id.is_none()
     }
 
     pub(crate) fn is_full(&self) -> bool {
         self.position >= self.max_file_size
     }
 
+    /// Returns the id of the first item in the section, if any.
+    pub(crate) fn first_id(&self) -> Option<u32> {
+        self.last_id.map(|_| self.data_start)
+    }
+
     pub(crate) fn last_id(&mut self) -> Option<u32> {
         self.last_id
     }
 
-    pub(crate) fn sync(&mut self) -> io::Result<()> {
-        self.buffer.flush()
+    /// Returns the id that the next appended item will be given.
+    pub(crate) fn next_id(&self) -> u32 {
+        self.position
     }
-}
 
-/// Given an open file and its total length, extract the last id
-/// that was written. Note that this by design only works with
-/// 32bit unsigned integers in length, so the caller must validate
-/// this before hand.
-fn last_id(file: &mut File, length: u32, read_chunk_size: u32) -> io::Result<Option<u32>> {
-    let mut buf = vec![0u8; read_chunk_size as usize];
-    let mut total = 0;
-    let mut items = 0;
+    /// Returns the position up to which data has been written to the
+    /// file, and so is visible to readers. This is behind `next_id`
+    /// while items are buffered, and catches up when they're flushed,
+    /// or when the buffer fills up and is written out.
+    pub(crate) fn written(&self) -> u32 {
+        self.position - (self.buffer.buffer().len() + self.encode_buffer.len()) as u32
+    }
+
+    /// Returns the sequence that the next appended item will be given,
+    /// if the section has sequences.
+    pub(crate) fn next_sequence(&self) -> Option<u64> {
+        self.first_sequence.map(|first| first + self.items)
+    }
+
+    /// Writes any buffered data to the file, making it visible
+    /// to readers. It may not yet have reached the disk.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if let Some(ref mut index) = self.index {
+            index.flush()?;
+        }
+
+        self.buffer.flush()?;
+        Ok(())
+    }
 
-    while total < length {
-        let pos = length - total;
+    /// Writes any buffered data to the file and waits for it to
+    /// reach the disk.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.buffer.get_ref().sync_data()?;
 
-        let starting_at = if pos < read_chunk_size {
-            0
+        if let Some(ref index) = self.index {
+            index.get_ref().sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn check_size(&self, data: &[u8]) -> Result<()> {
+        if data.len() > self.max_item_size as usize {
+            Err(DepotError::ItemTooLarge {
+                size: data.len(),
+                max_item_size: self.max_item_size,
+            })
         } else {
-            pos - read_chunk_size
-        };
+            Ok(())
+        }
+    }
 
-        let bytes_to_read = pos - starting_at;
-        file.seek(SeekFrom::Start(starting_at as u64))?;
-        file.read_exact(&mut buf[0..bytes_to_read as usize])?;
-        total += bytes_to_read;
+    /// Encodes an item, followed by its checksum and a separator, into
+    /// the encode buffer, returning its id. It's written to the file
+    /// via `write_encoded`.
+    fn encode(&mut self, data: &[u8]) -> u32 {
+        let id = self.position;
+        let start = self.encode_buffer.len();
 
-        let mut i = 1;
+        escape(data, &mut self.encode_buffer);
 
-        while i < bytes_to_read {
-            let p = bytes_to_read - i;
+        if self.checksums {
+            escape(
+                &crc32c::checksum(data).to_le_bytes(),
+                &mut self.encode_buffer,
+            );
+        }
+
+        self.encode_buffer.push(MARKER_SEPARATOR);
+
+        if self.index.is_some() {
+            self.index_buffer.extend_from_slice(&id.to_le_bytes());
+            self.items += 1;
+        }
 
-            if buf[p as usize] == MARKER_SEPARATOR && items > 0 {
-                return Ok(Some(pos - p));
-            } else if buf[p as usize] == MARKER_SEPARATOR {
-                items += 1;
+        // the size of an encoded item is bounded by max_file_size,
+        // which leaves room for it in a u32 -- see `max_file_size`
+        self.position += (self.encode_buffer.len() - start) as u32;
+        self.last_id = Some(id);
+
+        id
+    }
+
+    /// Writes any encoded items to the buffer. The encode buffer is
+    /// reused, but not if an unusually large item caused it to grow.
+    fn write_encoded(&mut self) -> Result<()> {
+        // Index entries reach the file before the items they refer to,
+        // so that readers can always find the entry of an item.
+        if let Some(ref mut index) = self.index {
+            index.write_all(&self.index_buffer)?;
+            self.index_buffer.clear();
+
+            if self.buffer.buffer().len() + self.encode_buffer.len() > self.buffer.capacity() {
+                index.flush()?;
             }
+        }
 
-            i += 1;
+        self.buffer.write_all(&self.encode_buffer)?;
+        self.encode_buffer.clear();
+
+        let capacity = self.buffer.capacity();
+
+        if self.encode_buffer.capacity() > capacity * 2 {
+            self.encode_buffer.shrink_to(capacity);
         }
+
+        Ok(())
+    }
+}
+
+/// Appends the provided bytes to the buffer, escaping any that
+/// collide with the markers of the on-disk format.
+///
+/// Markers are rare in most data, so they're searched for in bulk
+/// and the runs of bytes between them are copied as they are.
+fn escape(data: &[u8], buffer: &mut Vec<u8>) {
+    let mut start = 0;
+
+    buffer.reserve(data.len());
+
+    while let Some(n) = memchr3(MARKER_ESCAPE, MARKER_SEPARATOR, MARKER_FAIL, &data[start..]) {
+        let i = start + n;
+        let remapped = match data[i] {
+            MARKER_SEPARATOR => MARKER_SEPARATOR_REMAP,
+            MARKER_FAIL => MARKER_FAIL_REMAP,
+            _ => MARKER_ESCAPE,
+        };
+
+        buffer.extend_from_slice(&data[start..i]);
+        buffer.extend_from_slice(&[MARKER_ESCAPE, remapped]);
+        start = i + 1;
+    }
+
+    buffer.extend_from_slice(&data[start..]);
+}
+
+/// Verifies the checksum at the end of a decoded item, returning the
+/// length of its data and whether the checksum matched it. An item
+/// too short to contain a checksum doesn't match.
+fn verify(item: &[u8]) -> (usize, bool) {
+    if item.len() < CHECKSUM_SIZE {
+        return (item.len(), false);
     }
 
-    if length == 0 {
+    let len = item.len() - CHECKSUM_SIZE;
+    let mut expected = [0; CHECKSUM_SIZE];
+    expected.copy_from_slice(&item[len..]);
+
+    (
+        len,
+        crc32c::checksum(&item[0..len]) == u32::from_le_bytes(expected),
+    )
+}
+
+/// Decodes an escaped item in place, returning its decoded length, or
+/// the invalid byte that followed an escape. As with encoding, the
+/// escapes are searched for in bulk, and items without any are left
+/// untouched.
+///
+/// An escape at the very end of an item can't be decoded, and is
+/// ignored.
+fn unescape(data: &mut [u8]) -> result::Result<usize, u8> {
+    let mut read = 0;
+    let mut write = 0;
+
+    while let Some(n) = memchr(MARKER_ESCAPE, &data[read..]) {
+        let i = read + n;
+
+        if write != read {
+            data.copy_within(read..i, write);
+        }
+
+        write += i - read;
+
+        match data.get(i + 1) {
+            Some(&MARKER_ESCAPE) => data[write] = MARKER_ESCAPE,
+            Some(&MARKER_SEPARATOR_REMAP) => data[write] = MARKER_SEPARATOR,
+            Some(&MARKER_FAIL_REMAP) => data[write] = MARKER_FAIL,
+            Some(&byte) => return Err(byte),
+            None => return Ok(write),
+        }
+
+        write += 1;
+        read = i + 2;
+    }
+
+    if write != read {
+        data.copy_within(read.., write);
+    }
+
+    Ok(write + data.len() - read)
+}
+
+/// Given an open file, the position that its items start at and its
+/// total length, extract the last id that was written. Note that this
+/// by design only works with 32bit unsigned integers in length, so the
+/// caller must validate this before hand.
+///
+/// Trailing bytes without a separator are an item that is still being
+/// written, or was cut short by a crash, so they're ignored.
+fn last_id(file: &mut File, start: u32, length: u32, read_chunk_size: u32) -> Result<Option<u32>> {
+    let mut buf = vec![0u8; read_chunk_size as usize];
+    let mut end = length;
+    let mut separators = 0;
+
+    // Scan backwards from the end of the file. The last id starts
+    // directly after the second to last separator.
+    while end > start {
+        let begin = cmp::max(start, end.saturating_sub(read_chunk_size));
+        let bytes_to_read = (end - begin) as usize;
+        file.seek(SeekFrom::Start(begin as u64))?;
+        file.read_exact(&mut buf[0..bytes_to_read])?;
+
+        for p in (0..bytes_to_read).rev() {
+            if buf[p] == MARKER_SEPARATOR {
+                if separators > 0 {
+                    return Ok(Some(begin + p as u32 + 1));
+                }
+
+                separators += 1;
+            }
+        }
+
+        end = begin;
+    }
+
+    if separators == 0 {
         Ok(None)
-    } else if items == 0 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "file missing record separator",
-        ))
     } else {
-        Ok(Some(0))
+        Ok(Some(start))
+    }
+}
+
+/// Returns the path of the index of the section at the provided path.
+pub(crate) fn index_path(path: &Path) -> PathBuf {
+    path.with_extension(INDEX_EXTENSION)
+}
+
+/// Returns the number of complete entries in an index.
+fn index_len(index: &File) -> Result<u64> {
+    Ok(index.metadata()?.len() / INDEX_ENTRY_SIZE)
+}
+
+/// Reads the n-th entry of an index, which must exist.
+fn index_entry(index: &mut File, n: u64) -> Result<u32> {
+    let mut bytes = [0; INDEX_ENTRY_SIZE as usize];
+    index.seek(SeekFrom::Start(n * INDEX_ENTRY_SIZE))?;
+    index.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Searches the index of the section at the provided path for an
+/// item's id, returning its position in the index. Entries are in
+/// order, so this is a binary search.
+fn index_find(path: &Path, id: u32) -> Result<Option<u64>> {
+    let mut index = match File::open(index_path(path)) {
+        Ok(index) => index,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut low = 0;
+    let mut high = index_len(&index)?;
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let entry = index_entry(&mut index, mid)?;
+
+        if entry == id {
+            return Ok(Some(mid));
+        } else if entry < id {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Opens the index of a section with sequences, given its file, the
+/// position that its items start at and its total length, returning
+/// the index and its number of entries.
+///
+/// Entries are written before the items they refer to, so after a
+/// crash, the index may refer to items that never reached the disk,
+/// or to one that has since been marked as truncated. Those entries
+/// are removed, and any items that reached the disk without an entry
+/// are added, by scanning the items after the last valid entry.
+fn open_index(
+    path: &Path,
+    file: &mut File,
+    start: u32,
+    length: u32,
+    read_chunk_size: u32,
+) -> Result<(File, u64)> {
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(index_path(path))?;
+
+    let mut entries = index_len(&index)?;
+
+    while entries > 0 {
+        let id = index_entry(&mut index, entries - 1)?;
+
+        let valid = id >= start
+            && id < length
+            && (id == start || {
+                let mut byte = [0; 1];
+                file.seek(SeekFrom::Start(id as u64 - 1))?;
+                file.read_exact(&mut byte)?;
+                byte[0] == MARKER_SEPARATOR
+            });
+
+        if valid {
+            break;
+        }
+
+        entries -= 1;
+    }
+
+    // the item of the last valid entry is scanned again, as it may
+    // have been truncated
+    let scan_start = if entries > 0 {
+        entries -= 1;
+        index_entry(&mut index, entries)?
+    } else {
+        start
+    };
+
+    let ids = item_ids(file, scan_start, length, read_chunk_size)?;
+    let mut bytes = Vec::with_capacity(ids.len() * INDEX_ENTRY_SIZE as usize);
+
+    for id in &ids {
+        bytes.extend_from_slice(&id.to_le_bytes());
     }
+
+    index.set_len(entries * INDEX_ENTRY_SIZE)?;
+    index.write_all(&bytes)?;
+
+    Ok((index, entries + ids.len() as u64))
+}
+
+/// Returns the ids of the items in a file between the provided
+/// positions, which must be the start of an item and the end of the
+/// file's data, excluding truncated items.
+fn item_ids(file: &mut File, start: u32, end: u32, read_chunk_size: u32) -> Result<Vec<u32>> {
+    let mut buf = vec![0u8; read_chunk_size as usize];
+    let mut ids = Vec::new();
+    let mut item = start;
+    let mut position = start;
+    let mut previous = 0;
+
+    file.seek(SeekFrom::Start(start as u64))?;
+
+    while position < end {
+        let len = cmp::min(buf.len() as u32, end - position) as usize;
+        let mut from = 0;
+
+        file.read_exact(&mut buf[0..len])?;
+
+        while let Some(n) = memchr(MARKER_SEPARATOR, &buf[from..len]) {
+            let i = from + n;
+            let separator = position + i as u32;
+            let last = if i > 0 { buf[i - 1] } else { previous };
+
+            if separator == item || last != MARKER_FAIL {
+                ids.push(item);
+            }
+
+            item = separator + 1;
+            from = i + 1;
+        }
+
+        previous = buf[len - 1];
+        position += len as u32;
+    }
+
+    Ok(ids)
 }
 
 /*
@@ -556,6 +1605,463 @@ fn test_speed() {
 
 #[cfg(test)]
 mod tests {
-    // @TODO implement these. there is some coverage implicitly via queue tests
-    // @TODO but that's not good enough.
+    extern crate tempdir;
+
+    use section::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn read_all(path: &Path) -> Result<Vec<(u32, Vec<u8>)>> {
+        let path = path.to_path_buf();
+        let mut reader = SectionReader::new(path, 8388608, 8192, 8192, None)?;
+        let mut items = Vec::new();
+
+        loop {
+            reader.advance();
+
+            match reader.current()? {
+                Some(item) => {
+                    assert!(!item.corrupt);
+                    items.push((item.id, item.data.to_vec()));
+                }
+                None => return Ok(items),
+            }
+        }
+    }
+
+    fn read_data(path: &Path) -> Result<Vec<Vec<u8>>> {
+        read_all(path).map(|items| items.into_iter().map(|(_, data)| data).collect())
+    }
+
+    #[test]
+    fn test_escaped_round_trip() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+        let items: Vec<&[u8]> = vec![b"hello", b"\\n\n-\\-", b"", b"$.\\$"];
+
+        for &checksums in &[false, true] {
+            let _ = fs::remove_file(&path);
+
+            {
+                let mut writer =
+                    SectionWriter::new(&path, 8388608, 8192, 8192, 8192, checksums, None).unwrap();
+
+                for item in &items {
+                    writer.append(item).unwrap();
+                }
+
+                writer.sync().unwrap();
+            }
+
+            assert_eq!(read_data(&path).unwrap(), items);
+        }
+    }
+
+    #[test]
+    fn test_mapped() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+        let items: Vec<&[u8]> = vec![b"hello", b"\\n\n-\\-", b"", b"$.\\$", b"world"];
+
+        for &checksums in &[false, true] {
+            let _ = fs::remove_file(&path);
+
+            {
+                let mut writer =
+                    SectionWriter::new(&path, 8388608, 8192, 8192, 8192, checksums, None).unwrap();
+
+                for item in &items {
+                    writer.append(item).unwrap();
+                }
+
+                writer.sync().unwrap();
+            }
+
+            let expected = read_all(&path).unwrap();
+
+            // resuming from an item works as it does when buffered
+            for skip in 0..expected.len() {
+                let id = expected[skip].0;
+                let mut reader =
+                    SectionReader::new(path.clone(), 8388608, 8192, 8192, Some(id)).unwrap();
+                reader.map().unwrap();
+
+                let map_range = {
+                    let map = reader.map.as_ref().unwrap();
+                    map.as_ptr() as usize..map.as_ptr() as usize + map.len()
+                };
+
+                for (id, data) in &expected[skip..] {
+                    reader.advance();
+
+                    let item = reader.current().unwrap().unwrap();
+                    assert_eq!(item.id, *id);
+                    assert_eq!(item.data, &data[..]);
+                    assert!(!item.corrupt && !item.truncated);
+
+                    // items without escapes aren't copied
+                    let escaped = [MARKER_ESCAPE, MARKER_SEPARATOR, MARKER_FAIL];
+
+                    if !data.is_empty() && !data.iter().any(|b| escaped.contains(b)) {
+                        assert!(map_range.contains(&(item.data.as_ptr() as usize)));
+                    }
+                }
+
+                reader.advance();
+                assert!(reader.current().unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        let escaped = |data: &[u8]| {
+            let mut buffer = Vec::new();
+            escape(data, &mut buffer);
+            buffer
+        };
+
+        assert_eq!(escaped(b""), b"");
+        assert_eq!(escaped(b"hello"), b"hello");
+        assert_eq!(escaped(b"\\"), b"\\\\");
+        assert_eq!(escaped(b"a\nb-c\\"), b"a\\$b\\.c\\\\");
+        assert_eq!(escaped(b"\n\n--"), b"\\$\\$\\.\\.");
+
+        // every byte value, in runs long enough to be searched in bulk
+        let data: Vec<u8> = (0..4096).map(|i| (i * 7 % 256) as u8).collect();
+        let mut expected = Vec::new();
+
+        for &byte in &data {
+            match byte {
+                MARKER_ESCAPE => expected.extend_from_slice(&[MARKER_ESCAPE, MARKER_ESCAPE]),
+                MARKER_SEPARATOR => {
+                    expected.extend_from_slice(&[MARKER_ESCAPE, MARKER_SEPARATOR_REMAP])
+                }
+                MARKER_FAIL => expected.extend_from_slice(&[MARKER_ESCAPE, MARKER_FAIL_REMAP]),
+                other => expected.push(other),
+            }
+        }
+
+        assert_eq!(escaped(&data), expected);
+    }
+
+    #[test]
+    fn test_unescape() {
+        let unescaped = |data: &[u8]| {
+            let mut data = data.to_vec();
+            unescape(&mut data).map(|len| data[0..len].to_vec())
+        };
+
+        assert_eq!(unescaped(b""), Ok(b"".to_vec()));
+        assert_eq!(unescaped(b"hello"), Ok(b"hello".to_vec()));
+        assert_eq!(unescaped(b"a\\$b\\.c\\\\"), Ok(b"a\nb-c\\".to_vec()));
+        assert_eq!(unescaped(b"\\$\\$\\.\\."), Ok(b"\n\n--".to_vec()));
+        assert_eq!(unescaped(b"abc\\"), Ok(b"abc".to_vec()));
+        assert_eq!(unescaped(b"ab\\xc"), Err(b'x'));
+
+        let data: Vec<u8> = (0..4096).map(|i| (i * 7 % 256) as u8).collect();
+        let mut escaped = Vec::new();
+        escape(&data, &mut escaped);
+
+        assert_eq!(unescaped(&escaped), Ok(data));
+    }
+
+    #[test]
+    fn test_partial_item() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+
+        let mut writer = SectionWriter::new(&path, 8388608, 8192, 8192, 8192, false, None).unwrap();
+        writer.append(b"first").unwrap();
+        writer.sync().unwrap();
+
+        let mut reader = SectionReader::new(path.clone(), 8388608, 8192, 4, None).unwrap();
+        reader.advance();
+        assert_eq!(reader.current().unwrap().unwrap().data, b"first");
+
+        // the writer is part way through an item, so it isn't available
+        // yet, and isn't considered an error
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+
+        for part in &[&b"sec"[..], b"ond, with\\\\ an", b" escape\\", b"$"] {
+            file.write_all(part).unwrap();
+            reader.advance();
+            assert!(reader.current.is_ok());
+            assert!(reader.current().unwrap().is_none());
+        }
+
+        file.write_all(b"\nthird\n").unwrap();
+        reader.advance();
+        let item = reader.current().unwrap().unwrap();
+        assert_eq!(item.id, 38);
+        assert_eq!(item.data, b"second, with\\ an escape\n");
+
+        reader.advance();
+        assert_eq!(reader.current().unwrap().unwrap().data, b"third");
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+
+        {
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 8192, 8192, 8192, true, None).unwrap();
+            writer.append(b"first").unwrap();
+            writer.append(b"second").unwrap();
+            writer.sync().unwrap();
+        }
+
+        let mut contents = fs::read(&path).unwrap();
+        let position = contents.windows(6).position(|w| w == b"second").unwrap();
+        contents[position + 3] ^= 0x01;
+        fs::write(&path, &contents).unwrap();
+
+        let mut reader = SectionReader::new(path, 8388608, 8192, 8192, None).unwrap();
+
+        reader.advance();
+        assert_eq!(reader.current().unwrap().unwrap().data, b"first");
+
+        reader.advance();
+        let item = reader.current().unwrap().unwrap();
+        assert!(item.corrupt);
+        assert_eq!(item.data, b"secnnd");
+    }
+
+    #[test]
+    fn test_large_items() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+
+        let items: Vec<Vec<u8>> = (0..8)
+            .map(|n| (0..(n * 100_000)).map(|i| (i % 251) as u8).collect())
+            .collect();
+
+        {
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 1048576, 8192, 8192, true, None).unwrap();
+
+            for item in &items {
+                writer.append(item).unwrap();
+            }
+
+            assert!(writer.append(&vec![0; 1048577]).is_err());
+            writer.sync().unwrap();
+        }
+
+        let mut reader = SectionReader::new(path, 8388608, 8192, 16, None).unwrap();
+
+        for item in &items {
+            reader.advance();
+            assert_eq!(&reader.current().unwrap().unwrap().data, item);
+        }
+
+        reader.advance();
+        assert!(reader.current().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_header() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+
+        {
+            let mut writer = SectionWriter::new(&path, 1024, 512, 8192, 8192, true, None).unwrap();
+            assert!(writer.is_empty());
+            writer.append(b"first").unwrap();
+            writer.append(b"second").unwrap();
+            assert_eq!(writer.last_id(), Some(HEADER_SIZE as u32 + 10));
+            writer.sync().unwrap();
+        }
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(
+            SectionHeader::read(&mut file).unwrap(),
+            HeaderStatus::Complete(SectionHeader {
+                checksums: true,
+                first_sequence: None,
+                max_file_size: 1024,
+                max_item_size: 512,
+            })
+        );
+
+        {
+            // the header takes precedence over the supplied configuration
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 8192, 8192, 8192, false, None).unwrap();
+            assert_eq!(writer.last_id(), Some(HEADER_SIZE as u32 + 10));
+            assert!(writer.append(&[0; 1024]).is_err());
+            writer.append(b"third").unwrap();
+            writer.sync().unwrap();
+        }
+
+        assert_eq!(
+            read_all(&path).unwrap(),
+            vec![
+                (HEADER_SIZE as u32, b"first".to_vec()),
+                (HEADER_SIZE as u32 + 10, b"second".to_vec()),
+                (HEADER_SIZE as u32 + 21, b"third".to_vec()),
+            ]
+        );
+
+        let mut contents = fs::read(&path).unwrap();
+        contents[4] = 2;
+        fs::write(&path, &contents).unwrap();
+
+        assert!(SectionReader::new(path.clone(), 8388608, 8192, 8192, None).is_err());
+        assert!(SectionWriter::new(&path, 8388608, 8192, 8192, 8192, false, None).is_err());
+    }
+
+    #[test]
+    fn test_incomplete_header() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+        assert!(SectionWriter::writes_header(&path).unwrap());
+
+        fs::write(&path, &HEADER_MAGIC[0..3]).unwrap();
+        assert!(SectionWriter::writes_header(&path).unwrap());
+
+        let mut reader = SectionReader::new(path.clone(), 8388608, 8192, 8192, None).unwrap();
+        reader.advance();
+        assert!(reader.current().unwrap().is_none());
+
+        {
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 8192, 8192, 8192, false, None).unwrap();
+            assert!(writer.is_empty());
+            writer.append(b"first").unwrap();
+            writer.sync().unwrap();
+        }
+
+        assert!(!SectionWriter::writes_header(&path).unwrap());
+
+        reader.advance();
+        let item = reader.current().unwrap().unwrap();
+        assert_eq!(item.id, HEADER_SIZE as u32);
+        assert_eq!(item.data, b"first");
+    }
+
+    #[test]
+    fn test_sequences() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+        let index = index_path(&path);
+
+        let read_sequences = |id: Option<u32>| {
+            let mut reader = SectionReader::new(path.clone(), 8388608, 8192, 8192, id).unwrap();
+            let mut sequences = Vec::new();
+
+            loop {
+                reader.advance();
+
+                match reader.current().unwrap() {
+                    Some(item) => sequences.push((item.id, item.sequence)),
+                    None => return sequences,
+                }
+            }
+        };
+
+        {
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 8192, 8192, 8192, true, Some(100)).unwrap();
+            assert_eq!(writer.next_sequence(), Some(100));
+
+            writer.append(b"first").unwrap();
+            writer.append_batch(&[b"second", b"third"]).unwrap();
+            assert_eq!(writer.next_sequence(), Some(103));
+            writer.sync().unwrap();
+        }
+
+        let ids: Vec<u32> = read_all(&path).unwrap().iter().map(|i| i.0).collect();
+
+        assert_eq!(SectionReader::sequences(&path).unwrap(), Some((100, 3)));
+        assert_eq!(SectionReader::nth_id(&path, 1).unwrap(), Some(ids[1]));
+        assert_eq!(SectionReader::nth_id(&path, 3).unwrap(), None);
+
+        assert_eq!(
+            read_sequences(None),
+            vec![
+                (ids[0], Some(100)),
+                (ids[1], Some(101)),
+                (ids[2], Some(102))
+            ]
+        );
+
+        // resuming mid-section looks the sequence up in the index
+        assert_eq!(
+            read_sequences(Some(ids[1])),
+            vec![(ids[1], Some(101)), (ids[2], Some(102))]
+        );
+
+        // after a crash, the index may refer to an item that never
+        // reached the disk, and a truncated item doesn't get a sequence
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"fourt").unwrap();
+
+            let end = fs::metadata(&path).unwrap().len() as u32;
+            let mut file = OpenOptions::new().append(true).open(&index).unwrap();
+            file.write_all(&(end - 5).to_le_bytes()).unwrap();
+            file.write_all(&(end + 10).to_le_bytes()).unwrap();
+        }
+
+        {
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 8192, 8192, 8192, true, None).unwrap();
+            assert_eq!(writer.next_sequence(), Some(103));
+
+            writer.append(b"fifth").unwrap();
+            writer.sync().unwrap();
+        }
+
+        let items = read_sequences(None);
+        assert_eq!(items.len(), 5);
+        assert_eq!(items[3].1, None);
+        assert_eq!(items[4].1, Some(103));
+        assert_eq!(SectionReader::sequences(&path).unwrap(), Some((100, 4)));
+
+        // a missing index is rebuilt by the writer
+        fs::remove_file(&index).unwrap();
+        assert_eq!(read_sequences(Some(items[4].0)), vec![(items[4].0, None)]);
+
+        {
+            let writer = SectionWriter::new(&path, 8388608, 8192, 8192, 8192, true, None).unwrap();
+            assert_eq!(writer.next_sequence(), Some(104));
+        }
+
+        assert_eq!(SectionReader::nth_id(&path, 3).unwrap(), Some(items[4].0));
+        assert_eq!(
+            read_sequences(Some(items[4].0)),
+            vec![(items[4].0, Some(103))]
+        );
+    }
+
+    #[test]
+    fn test_legacy() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let path = tmp_dir.path().join("d0");
+
+        fs::write(&path, b"first\n\\\\sec\\$ond\nthi").unwrap();
+        assert!(!SectionWriter::writes_header(&path).unwrap());
+
+        {
+            let mut writer =
+                SectionWriter::new(&path, 8388608, 8192, 8192, 8192, true, None).unwrap();
+            assert_eq!(writer.last_id(), Some(17));
+            writer.append(b"fourth").unwrap();
+            assert_eq!(writer.last_id(), Some(23));
+            writer.sync().unwrap();
+        }
+
+        assert_eq!(
+            read_all(&path).unwrap(),
+            vec![
+                (0, b"first".to_vec()),
+                (6, b"\\sec\nond".to_vec()),
+                (17, b"thi--".to_vec()),
+                (23, b"fourth".to_vec()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,229 @@
+use std::cmp;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use libc;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+
+/// How often to check for changes when they can't be watched.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Waits for changes to a section file, or for new entries in the
+/// directories that contain it, e.g. a writer appending to the
+/// section or moving on to a new one.
+///
+/// On Linux, this uses inotify. Elsewhere, or if none of the paths
+/// can be watched (e.g. they don't exist yet), it polls instead.
+///
+/// Closing an inotify instance is slow, as the kernel waits for a
+/// grace period, so a watcher is kept for the life of a streamer
+/// and its watches are updated as it moves between sections.
+pub(crate) struct Watcher {
+    #[cfg(target_os = "linux")]
+    fd: libc::c_int,
+
+    #[cfg(target_os = "linux")]
+    file: Option<(PathBuf, libc::c_int)>,
+
+    #[cfg(target_os = "linux")]
+    dirs: Vec<libc::c_int>,
+
+    #[cfg(target_os = "linux")]
+    watched: bool,
+}
+
+impl Watcher {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new() -> io::Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Watcher {
+            fd,
+            file: None,
+            dirs: Vec::new(),
+            watched: false,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn new() -> io::Result<Watcher> {
+        Ok(Watcher {})
+    }
+
+    /// Watches the provided section file and directories, replacing
+    /// the watches for any previous ones. Changes are noticed
+    /// once this returns, so a caller should call this before it
+    /// checks for new data, and only wait if none was found.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn watch(&mut self, file: &Path, dirs: &[&Path]) {
+        if let Some((ref path, wd)) = self.file {
+            if path != file {
+                unsafe {
+                    libc::inotify_rm_watch(self.fd, wd);
+                }
+            }
+        }
+
+        self.file = match self.file.take() {
+            Some((path, wd)) if path == file => Some((path, wd)),
+            _ => add_watch(self.fd, file, libc::IN_MODIFY).map(|wd| (file.to_owned(), wd)),
+        };
+
+        // Watching a directory that is already watched returns its
+        // existing descriptor, so any that aren't returned again are
+        // for directories that the section is no longer in.
+        let wds: Vec<libc::c_int> = dirs
+            .iter()
+            .filter_map(|dir| add_watch(self.fd, dir, libc::IN_CREATE | libc::IN_MOVED_TO))
+            .collect();
+
+        for wd in &self.dirs {
+            if !wds.contains(wd) {
+                unsafe {
+                    libc::inotify_rm_watch(self.fd, *wd);
+                }
+            }
+        }
+
+        self.watched = self.file.is_some() || !wds.is_empty();
+        self.dirs = wds;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn watch(&mut self, _file: &Path, _dirs: &[&Path]) {}
+
+    /// Blocks until a change may have occurred, or the timeout has
+    /// elapsed. Spurious wakeups are possible, so the caller must
+    /// check for new data regardless.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        if !self.watched {
+            return poll_wait(timeout);
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        // round up, so that we don't wake before the timeout and spin
+        let millis = timeout.as_nanos().div_ceil(1_000_000);
+        let millis = cmp::min(millis, libc::c_int::MAX as u128) as libc::c_int;
+
+        if unsafe { libc::poll(&mut pollfd, 1, millis) } < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+
+        // The events themselves aren't needed, as the caller checks
+        // for new data regardless, so discard them.
+        let mut buf = [0u8; 4096];
+
+        while unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        poll_wait(timeout)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Adds an inotify watch for the provided path, returning its
+/// descriptor. Paths that don't exist are expected, and other
+/// failures (e.g. the watch limit being reached) are treated the
+/// same way, as the caller can fall back to polling.
+#[cfg(target_os = "linux")]
+fn add_watch(fd: libc::c_int, path: &Path, mask: u32) -> Option<libc::c_int> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+
+    match unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } {
+        wd if wd >= 0 => Some(wd),
+        _ => None,
+    }
+}
+
+fn poll_wait(timeout: Duration) -> io::Result<()> {
+    thread::sleep(cmp::min(timeout, POLL_INTERVAL));
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs;
+    use std::time::Instant;
+    use watch::*;
+
+    /// Returns the number of watches of an inotify instance.
+    fn watches(watcher: &Watcher) -> usize {
+        fs::read_to_string(format!("/proc/self/fdinfo/{}", watcher.fd))
+            .unwrap()
+            .lines()
+            .filter(|line| line.starts_with("inotify"))
+            .count()
+    }
+
+    #[test]
+    fn test_watch() {
+        let tmp_dir = tempdir::TempDir::new("depot-tests").unwrap();
+        let one = tmp_dir.path().join("d0");
+        let two = tmp_dir.path().join("d1");
+        fs::create_dir(&one).unwrap();
+        fs::create_dir(&two).unwrap();
+        fs::write(one.join("d0"), b"").unwrap();
+        fs::write(two.join("d0"), b"").unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.watch(&one.join("d0"), &[&one, tmp_dir.path()]);
+        assert_eq!(watches(&watcher), 3);
+
+        // watching the same paths again doesn't add watches
+        watcher.watch(&one.join("d0"), &[&one, tmp_dir.path()]);
+        assert_eq!(watches(&watcher), 3);
+
+        // the previous file and directory are no longer watched
+        watcher.watch(&two.join("d0"), &[&two, tmp_dir.path()]);
+        assert_eq!(watches(&watcher), 3);
+
+        // paths that don't exist can't be watched
+        watcher.watch(&two.join("d1"), &[&two.join("d1"), tmp_dir.path()]);
+        assert_eq!(watches(&watcher), 1);
+        assert!(watcher.watched);
+
+        // changes to watched paths are noticed
+        watcher.watch(&two.join("d0"), &[&two, tmp_dir.path()]);
+        fs::write(two.join("d1"), b"").unwrap();
+        let start = Instant::now();
+        watcher.wait(Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}